@@ -2,7 +2,7 @@
 // Sanxion.
 use lattefm::instrument::{Instrument, Waveform};
 use lattefm::channel::Command;
-use lattefm::tune::Tune;
+use lattefm::tune::{Tune, Song};
 use lattefm::player::Player;
 use sdl2;
 use sdl2::audio::{AudioCallback, AudioSpecDesired};
@@ -19,8 +19,7 @@ const INSTRUMENTS: [Instrument; 6] = [
         modulator_waveform: Waveform::Sine,
         modulator_amplitude: u16::MAX/5,
         modulator_mul: 4,
-        modulator_div: 1,
-        modulator_phase: 0
+        ..Instrument::DEFAULT
     },
     Instrument{ // Tweet
         carrier_waveform: Waveform::Sine,
@@ -32,8 +31,7 @@ const INSTRUMENTS: [Instrument; 6] = [
         modulator_waveform: Waveform::Sine,
         modulator_amplitude: u16::MAX/2,
         modulator_mul: 4,
-        modulator_div: 1,
-        modulator_phase: 0
+        ..Instrument::DEFAULT
     },
     Instrument{ // Bass
         carrier_waveform: Waveform::Triangle,
@@ -44,9 +42,8 @@ const INSTRUMENTS: [Instrument; 6] = [
         release: (1<<12)/32,
         modulator_waveform: Waveform::Triangle,
         modulator_amplitude: u16::MAX/2,
-        modulator_mul: 1,
         modulator_div: 2,
-        modulator_phase: 0
+        ..Instrument::DEFAULT
     },
     Instrument{ // Hihat
         carrier_waveform: Waveform::Noise,
@@ -54,12 +51,8 @@ const INSTRUMENTS: [Instrument; 6] = [
         attack: (1<<12)/64,
         decay: (1<<12)/8,
         sustain: 0,
-        release: 0,
         modulator_waveform: Waveform::Saw,
-        modulator_amplitude: 0,
-        modulator_mul: 1,
-        modulator_div: 1,
-        modulator_phase: 0
+        ..Instrument::DEFAULT
     },
     Instrument{ // Bass drum
         carrier_waveform: Waveform::Sine,
@@ -67,12 +60,10 @@ const INSTRUMENTS: [Instrument; 6] = [
         attack: (1<<12)/64,
         decay: (1<<12)/8,
         sustain: 0,
-        release: 0,
         modulator_waveform: Waveform::Sine,
         modulator_amplitude: u16::MAX/4,
-        modulator_mul: 1,
         modulator_div: 4,
-        modulator_phase: 0
+        ..Instrument::DEFAULT
     },
     Instrument{ // Lead 2
         carrier_waveform: Waveform::Sine,
@@ -84,8 +75,7 @@ const INSTRUMENTS: [Instrument; 6] = [
         modulator_waveform: Waveform::Sine,
         modulator_amplitude: u16::MAX/3,
         modulator_mul: 2,
-        modulator_div: 1,
-        modulator_phase: 0
+        ..Instrument::DEFAULT
     },
 ];
 
@@ -228,13 +218,16 @@ const CHANNELS: [&'static[Command]; 5]= [&CHANNEL0, &CHANNEL1, &CHANNEL2, &CHANN
 
 const EXAMPLE: Tune = Tune{
     samplerate: 8192,
-    tick_length: 1024,
     instruments: &INSTRUMENTS,
-    channels: &CHANNELS
+    wavetables: &[],
+    songs: &[Song{
+        tick_length: 1024<<8,
+        channels: &CHANNELS
+    }]
 };
 
 struct MyPlayer<'a> {
-    player: Player<'a>
+    player: Player<'a, 'static>
 }
 
 impl<'a> AudioCallback for MyPlayer<'a> {
@@ -7,6 +7,46 @@ use lattefm::instrument::Waveform;
 use std::collections::HashMap;
 use std::cmp::Ordering;
 
+// LatteFM has no falling saw, so LFOs that need one get this wavetable. It's
+// only included in the output if used.
+pub const RAMP_DOWN: Waveform = Waveform::Table(0);
+const RAMP_DOWN_LENGTH: i32 = 64;
+
+#[derive(Clone, PartialEq)]
+pub struct Lfo {
+    waveform: Waveform,
+    rate: f64, // in Hz
+    depth: f64,
+    fade_in: f64, // in seconds
+}
+
+impl Lfo {
+    fn to_source(&self) -> String {
+        format!(
+            "LFO {{ waveform: Waveform::{:?}, rate: {}, depth: {}, fade_in: {} }}",
+            self.waveform,
+            (self.rate * 256.0).round() as u16,
+            (self.depth * 65536.0).round().min(u16::MAX as f64) as u16,
+            (self.fade_in * ((1<<12) as f64)) as u16
+        )
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct SampleData {
+    data: Vec<i8>,
+    rate: f64, // in Hz, when the note is C4
+    loop_start: u32,
+    loop_length: u32,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct EnvelopeData {
+    points: Vec<(f64, f64)>, // Time in seconds and level relative to amplitude
+    sustain: Option<usize>,
+    loop_points: Option<(usize, usize)>,
+}
+
 #[derive(Clone, PartialEq)]
 pub struct Instrument {
     carrier_waveform: Waveform,
@@ -15,13 +55,16 @@ pub struct Instrument {
     decay: f64,
     sustain: f64,
     release: f64,
+    envelope: Option<EnvelopeData>, // Replaces the ADSR if set.
     modulator_waveform: Waveform,
     modulator_amplitude: f64,
     modulator_mul: i64,
     modulator_div: i64,
     modulator_phase: f64,
+    feedback: u8,
+    vibrato: Option<Lfo>,
+    sample: Option<SampleData>, // If set, the instrument plays this instead of FM.
     semitone_offset: f64, // Used to correct sample pitches
-    release_is_sustain: bool
 }
 
 impl Default for Instrument {
@@ -33,13 +76,16 @@ impl Default for Instrument {
             decay: 0.0,
             sustain: 0.0,
             release: 0.0,
+            envelope: None,
             modulator_waveform: Waveform::Sine,
             modulator_amplitude: 0.0,
             modulator_mul: 1,
             modulator_div: 1,
             modulator_phase: 0.0,
+            feedback: 0,
+            vibrato: None,
+            sample: None,
             semitone_offset: 0.0,
-            release_is_sustain: false
         }
     }
 }
@@ -48,8 +94,7 @@ impl Instrument {
     pub fn fit_adsr(
         &mut self,
         envelope_points: &Vec<(f64, f64)>,
-        sustain_point_index: i64,
-        looping: bool
+        sustain_point_index: i64
     ) {
         if envelope_points.len() != 0 {
             let local_sustain_point_index = if sustain_point_index < 0 {
@@ -85,17 +130,57 @@ impl Instrument {
             self.decay = sustain_t - peak_t;
             self.sustain = sustain_vol;
             self.release = envelope_points.last().unwrap().0 - sustain_t;
-            self.release_is_sustain = looping;
         } else {
             self.amplitude = 1.0;
             self.attack = 0.0;
             self.decay = 0.0;
             self.sustain = 1.0;
             self.release = 0.0;
-            self.release_is_sustain = false;
         }
     }
 
+    // Sets an exact envelope. fit_adsr should still be called first, the
+    // levels are stored relative to the amplitude found by it.
+    pub fn set_envelope(
+        &mut self,
+        envelope_points: &Vec<(f64, f64)>,
+        sustain: Option<usize>,
+        loop_points: Option<(usize, usize)>
+    ) {
+        let amplitude = self.amplitude;
+        self.envelope = Some(EnvelopeData {
+            points: envelope_points.iter().map(
+                |&(t, vol)| (t, if amplitude > 0.0 {vol / amplitude} else {0.0})
+            ).collect(),
+            sustain,
+            loop_points
+        });
+    }
+
+    pub fn set_vibrato(
+        &mut self,
+        waveform: Waveform,
+        rate: f64,
+        depth: f64,
+        fade_in: f64
+    ) {
+        self.vibrato = if rate > 0.0 && depth > 0.0 {
+            Some(Lfo { waveform, rate, depth, fade_in })
+        } else {
+            None
+        };
+    }
+
+    pub fn set_sample(
+        &mut self,
+        data: Vec<i8>,
+        rate: f64,
+        loop_start: u32,
+        loop_length: u32
+    ) {
+        self.sample = Some(SampleData { data, rate, loop_start, loop_length });
+    }
+
     pub fn fit_to_sample(
         &mut self,
         sample_data: &Vec<f64>,
@@ -112,7 +197,7 @@ impl Instrument {
 }
 
 pub const PAUSE: u32 = 255;
-// Release may become PAUSE during processing, this depends on the instrument.
+// Release becomes KeyOff during processing.
 pub const RELEASE: u32 = 256;
 
 #[derive(Debug, PartialEq)]
@@ -120,13 +205,32 @@ pub enum Command {
     Note(u32),
     SetInstrument(u32),
     SetVolume(u32),
-    Play(u32),
+    Play(u32), // Becomes Hold or Wait during processing.
+    Hold(u32),
+    Wait(u32),
+    KeyOff,
+    SlideUp(u32),
+    SlideDown(u32),
+    Portamento(u32),
+    Arpeggio(u32, u32, u32),
+    SetArpeggioRate(u32),
+    SetArpeggioSteps(u32),
     JumpTick(u32), // Unlike LatteFM, this jumps to a specific tick.
     Jump(u32), // This one jumps normally to a specific command.
     Repeat(u32),
     Pan(i8),
 }
 
+impl Command {
+    // How many ticks the command steps ahead.
+    fn ticks(&self) -> u32 {
+        match *self {
+            Command::Play(ticks) | Command::Hold(ticks) | Command::Wait(ticks) => ticks,
+            _ => 0
+        }
+    }
+}
+
 pub struct Module {
     pub tick_length: f64, // in seconds
     pub instruments: Vec<Instrument>,
@@ -256,6 +360,10 @@ impl Module {
                         }
                         cur_pan = p;
                     },
+                    // The pitch may have moved away from the note.
+                    Command::SlideUp(_) | Command::SlideDown(_) | Command::Portamento(_) => {
+                        cur_note = u32::MAX;
+                    },
                     _ => ()
                 }
                 i += 1
@@ -273,7 +381,7 @@ impl Module {
             for command in channel.iter() {
                 match *command {
                     Command::Note(n) if n != PAUSE => has_note = true,
-                    Command::Play(t) if t != 0 => has_play = true,
+                    ref c if c.ticks() != 0 => has_play = true,
                     _ => ()
                 }
             }
@@ -286,13 +394,14 @@ impl Module {
         for channel in self.channels.iter_mut() {
             let mut i = 0;
             while i < channel.len() {
-                if let Command::Play(ticks) = channel[i] {
-                    if ticks > (u8::MAX as u32) {
-                        channel[i] = Command::Play(ticks/2);
-                        channel.insert(i+1, Command::Play((ticks+1)/2));
-                    } else {
-                        i += 1;
-                    }
+                let ticks = channel[i].ticks();
+                if ticks > (u8::MAX as u32) {
+                    // The second half must not restart the note.
+                    channel[i] = match channel[i] {
+                        Command::Hold(_) => Command::Hold(ticks/2),
+                        _ => Command::Wait(ticks/2)
+                    };
+                    channel.insert(i+1, Command::Wait((ticks+1)/2));
                 } else {
                     i += 1;
                 }
@@ -317,11 +426,8 @@ impl Module {
             let mut i = 0;
             while i < channel.len() {
                 let prev_tick = tick;
-                let mut step_ticks = 0;
-                if let Command::Play(ticks) = channel[i] {
-                    step_ticks = ticks;
-                    tick += step_ticks;
-                }
+                let step_ticks = channel[i].ticks();
+                tick += step_ticks;
 
                 // In the same loop, we handle the easy cases where the ticks fall
                 // in between commands nicely and save the earliest required
@@ -335,10 +441,13 @@ impl Module {
                 }
 
                 if earliest_split != 0 {
-                    channel[i] = Command::Play(earliest_split);
+                    channel[i] = match channel[i] {
+                        Command::Hold(_) => Command::Hold(earliest_split),
+                        _ => Command::Wait(earliest_split)
+                    };
                     channel.insert(
                         i+1,
-                        Command::Play(step_ticks-earliest_split)
+                        Command::Wait(step_ticks-earliest_split)
                     );
                 }
 
@@ -353,9 +462,7 @@ impl Module {
                         *target_index = i as i32;
                     }
                 }
-                if let Command::Play(ticks) = command {
-                    tick += ticks;
-                }
+                tick += command.ticks();
             }
 
             // Translate all JumpTicks into regular Jumps
@@ -369,44 +476,31 @@ impl Module {
     }
 
     fn resolve_release(mut self) -> Self {
+        // Notes sustain until a release, which lets the envelope ring out
+        // without restarting the note. Notes reached by portamento don't
+        // restart either.
         for channel in self.channels.iter_mut() {
-            let mut release_is_sustain = false;
-
-            // First pass: convert releases that should be pauses, into pauses.
+            let mut new_note = false;
+            let mut portamento = false;
             for command in channel.iter_mut() {
-                match command {
-                    Command::SetInstrument(ins) => {
-                        release_is_sustain = self.instruments[*ins as usize].release_is_sustain;
+                match *command {
+                    Command::Note(key) if key == RELEASE => {
+                        *command = Command::KeyOff;
+                        new_note = false;
                     },
-                    Command::Note(key) if *key == RELEASE => {
-                        if !release_is_sustain {
-                            *key = PAUSE;
-                        }
+                    Command::Note(_) => new_note = !portamento,
+                    Command::Portamento(speed) => portamento = speed != 0,
+                    Command::Play(ticks) => {
+                        *command = if new_note {
+                            Command::Hold(ticks)
+                        } else {
+                            Command::Wait(ticks)
+                        };
+                        new_note = false;
                     },
                     _ => {}
                 }
             }
-
-            // Second pass: remove remaining releases, merge their surrounding
-            // plays.
-            let mut i = 0;
-            while i < channel.len() {
-                if channel[i] == Command::Note(RELEASE) {
-                    channel.remove(i);
-                    // Why doesn't if let support &&, that seems like such an
-                    // obvious defect...
-                    if i > 0 {
-                        if let Command::Play(b_ticks) = channel[i] {
-                            if let Command::Play(a_ticks) = &mut channel[i-1] {
-                                *a_ticks += b_ticks;
-                                channel.remove(i);
-                            }
-                        }
-                    }
-                } else {
-                    i += 1;
-                }
-            }
         }
         self
     }
@@ -462,16 +556,53 @@ impl Module {
     }
 
     pub fn print_as_source(&self) {
+        let ramp_down = self.instruments.iter().any(
+            |ins| ins.vibrato.as_ref().is_some_and(|v| v.waveform == RAMP_DOWN)
+        );
         println!(
             concat!(
-                "use lattefm::instrument::{{Instrument, Waveform}};\n",
-                "use lattefm::tune::Tune;\n",
-                "use lattefm::channel::{{Command, note_names::*, Command::*}};\n\n",
-                "const INSTRUMENTS: [Instrument; {}] = ["
-            ),
-            self.instruments.len()
+                "use lattefm::instrument::{{Instrument, InstrumentKind, Waveform, Envelope, LFO, Sample}};\n",
+                "use lattefm::tune::{{Tune, Song}};\n",
+                "use lattefm::channel::{{Command, note_names::*, Command::*}};"
+            )
         );
-        for ins in self.instruments.iter() {
+        if ramp_down {
+            println!("use lattefm::wave::Wavetable;\n");
+            println!(
+                "const WAVETABLES: [Wavetable; 1] = [Wavetable::I8(&[{}])];\n",
+                (0..RAMP_DOWN_LENGTH)
+                    .map(|i| (127 - i * 255 / (RAMP_DOWN_LENGTH - 1)).to_string())
+                    .collect::<Vec<String>>().join(", ")
+            );
+        } else {
+            println!();
+        }
+
+        for (i, ins) in self.instruments.iter().enumerate() {
+            if let Some(ref envelope) = ins.envelope {
+                println!(
+                    "const ENVELOPE{}: [(u16, u16); {}] = [{}];\n",
+                    i,
+                    envelope.points.len(),
+                    envelope.points.iter().map(|(t, level)| format!(
+                        "({}, {})",
+                        (t * ((1<<12) as f64)).round().min(u16::MAX as f64) as u16,
+                        (level * (u16::MAX as f64)).round().min(u16::MAX as f64) as u16
+                    )).collect::<Vec<String>>().join(", ")
+                );
+            }
+            if let Some(ref sample) = ins.sample {
+                println!(
+                    "const SAMPLE{}: [i8; {}] = [{}];\n",
+                    i,
+                    sample.data.len(),
+                    sample.data.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", ")
+                );
+            }
+        }
+
+        println!("const INSTRUMENTS: [Instrument; {}] = [", self.instruments.len());
+        for (i, ins) in self.instruments.iter().enumerate() {
             println!(
                 concat!(
                     "    Instrument {{\n",
@@ -481,11 +612,16 @@ impl Module {
                     "        decay: {},\n",
                     "        sustain: {},\n",
                     "        release: {},\n",
+                    "        envelope: {},\n",
                     "        modulator_waveform: Waveform::{:?},\n",
                     "        modulator_amplitude: {},\n",
                     "        modulator_mul: {},\n",
                     "        modulator_div: {},\n",
                     "        modulator_phase: {},\n",
+                    "        feedback: {},\n",
+                    "        vibrato: {},\n",
+                    "        kind: {},\n",
+                    "        ..Instrument::DEFAULT\n",
                     "    }},"
                 ),
                 ins.carrier_waveform,
@@ -494,11 +630,26 @@ impl Module {
                 (ins.decay * ((1<<12) as f64)) as u16,
                 (ins.sustain * (u16::MAX as f64)).floor() as u16,
                 (ins.release * ((1<<12) as f64)) as u16,
+                ins.envelope.as_ref().map_or(String::from("None"), |envelope| format!(
+                    "Some(Envelope {{ points: &ENVELOPE{}, sustain: {:?}, loop_points: {:?} }})",
+                    i,
+                    envelope.sustain,
+                    envelope.loop_points
+                )),
                 ins.modulator_waveform,
                 (ins.modulator_amplitude * (u16::MAX as f64)).floor() as u16,
                 ins.modulator_mul,
                 ins.modulator_div,
-                (ins.modulator_phase * (u16::MAX as f64)).floor() as u16
+                (ins.modulator_phase * (u16::MAX as f64)).floor() as u16,
+                ins.feedback,
+                ins.vibrato.as_ref().map_or(String::from("None"), |v| format!("Some({})", v.to_source())),
+                ins.sample.as_ref().map_or(String::from("InstrumentKind::TwoOp"), |sample| format!(
+                    "InstrumentKind::Sample(Sample {{ data: &SAMPLE{}, rate: {}, loop_start: {}, loop_length: {} }})",
+                    i,
+                    sample.rate.round() as u32,
+                    sample.loop_start,
+                    sample.loop_length
+                ))
             );
         }
         println!("];\n");
@@ -533,13 +684,17 @@ impl Module {
             concat!(
                 "pub const TUNE: Tune = Tune {{\n",
                 "    samplerate: {},\n",
-                "    tick_length: {},\n",
                 "    instruments: &INSTRUMENTS,\n",
-                "    channels: &CHANNELS,\n",
+                "    wavetables: {},\n",
+                "    songs: &[Song {{\n",
+                "        tick_length: {},\n",
+                "        channels: &CHANNELS,\n",
+                "    }}],\n",
                 "}};"
             ),
             44100,
-            (44100.0 * self.tick_length).round() as i32
+            if ramp_down {"&WAVETABLES"} else {"&[]"},
+            (44100.0 * 256.0 * self.tick_length).round() as i32
         );
     }
 }
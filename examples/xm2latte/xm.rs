@@ -3,6 +3,7 @@ use binread::{BinResult, io::{Read, Seek, SeekFrom}};
 use std::fs;
 use std::collections::HashMap;
 use crate::intermediate;
+use lattefm::instrument::Waveform;
 
 // https://github.com/milkytracker/MilkyTracker/blob/master/resources/reference/xm-form.txt
 #[derive(BinRead)]
@@ -36,6 +37,10 @@ struct Note {
 // If Note::note is KEY_OFF, the release period of that note occurs.
 const KEY_OFF: u8 =  97;
 
+// Samples up to this length are played back as-is, longer ones are
+// approximated with FM.
+const MAX_PASSTHROUGH_SAMPLE_LENGTH: usize = 16384;
+
 impl Default for Note {
     fn default() -> Note {
         Note{
@@ -157,8 +162,9 @@ impl Sample {
         let headers = &args.0;
         let mut samples: Vec<Sample> = vec![];
         for header in headers {
-            if (header.sample_type&0x10) == 1 {
-                let mut data: Vec<i16> = vec![0; header.length as usize];
+            // The length is in bytes for 16-bit samples too.
+            if (header.sample_type&0x10) != 0 {
+                let mut data: Vec<i16> = vec![0; header.length as usize / 2];
                 // TODO: Remove code duplication with generics (but seems like
                 // Rust doesn't make such a simple thing easy because who would
                 // want to make integer size generic -.-)
@@ -275,21 +281,80 @@ impl From<File> for intermediate::Module {
                     -1
                 };
 
-                fit_ins.fit_adsr(&envelope_points, sustain_index, looping);
+                fit_ins.fit_adsr(&envelope_points, sustain_index);
+                if (extra.volume_type&1) != 0 && envelope_points.len() != 0 {
+                    fit_ins.set_envelope(
+                        &envelope_points,
+                        if (extra.volume_type&2) != 0 {
+                            Some(extra.volume_sustain_point as usize)
+                        } else {
+                            None
+                        },
+                        if looping {
+                            Some((extra.volume_loop_start_point as usize, extra.volume_loop_end_point as usize))
+                        } else {
+                            None
+                        }
+                    );
+                }
+
+                // XM auto-vibrato advances once per tick, 256 steps per cycle.
+                // The depth is in 1/64 semitones and the sweep in ticks.
+                let xm_tick_length = 2.5/(xm.header.bpm as f64);
+                fit_ins.set_vibrato(
+                    match extra.vibrato_type {
+                        0 => Waveform::Sine,
+                        1 => Waveform::Square,
+                        2 => intermediate::RAMP_DOWN,
+                        _ => Waveform::Saw
+                    },
+                    extra.vibrato_rate as f64 / 256.0 / xm_tick_length,
+                    f64::powf(2.0, extra.vibrato_depth as f64 / 768.0) - 1.0,
+                    extra.vibrato_sweep as f64 * xm_tick_length
+                );
 
                 for sample_index in 0..(ins.num_samples as usize) {
                     let sample_data: Vec<f64>;
                     let header = &ins.sample_headers[sample_index];
                     let mut sample_ins = fit_ins.clone();
+                    let pcm: Vec<i8>;
+                    // Loop points are in bytes.
+                    let bytes_per_sample;
                     match &ins.samples[sample_index] {
-                        Sample::Depth8(data) => sample_data = data.iter().map(|x| *x as f64/128.0).collect(),
-                        Sample::Depth16(data) => sample_data = data.iter().map(|x| *x as f64/32768.0).collect(),
+                        Sample::Depth8(data) => {
+                            sample_data = data.iter().map(|x| *x as f64/128.0).collect();
+                            pcm = data.clone();
+                            bytes_per_sample = 1;
+                        },
+                        Sample::Depth16(data) => {
+                            sample_data = data.iter().map(|x| *x as f64/32768.0).collect();
+                            pcm = data.iter().map(|x| (x >> 8) as i8).collect();
+                            bytes_per_sample = 2;
+                        },
+                    }
+                    // LatteFM samples only loop forwards, so ping-pong loops
+                    // are approximated with FM instead.
+                    let ping_pong = (header.sample_type&3) == 2;
+                    if sample_data.len() <= MAX_PASSTHROUGH_SAMPLE_LENGTH && !ping_pong {
+                        // XM samples play at 8363 Hz at C4, before the
+                        // relative note and finetune are applied.
+                        let looping = (header.sample_type&3) != 0;
+                        sample_ins.set_sample(
+                            pcm,
+                            8363.0 * f64::powf(2.0, (
+                                (header.relative_note_number as i8 as f64) +
+                                (header.finetune as i8 as f64/128.0)
+                            ) / 12.0),
+                            header.loop_start / bytes_per_sample,
+                            if looping {header.loop_length / bytes_per_sample} else {0}
+                        );
+                    } else {
+                        sample_ins.fit_to_sample(
+                            &sample_data,
+                            (header.relative_note_number as f64) +
+                            (header.finetune as f64/128.0),
+                        );
                     }
-                    sample_ins.fit_to_sample(
-                        &sample_data,
-                        (header.relative_note_number as f64) +
-                        (header.finetune as f64/128.0),
-                    );
                     sample_instrument_table.insert(
                         (ins_index, sample_index),
                         m.instruments.len() as u32
@@ -308,6 +373,16 @@ impl From<File> for intermediate::Module {
             let mut cur_volume = 255;
             let mut tick_counter = 0;
 
+            // Slides in LatteFM units, positive is up. Effect parameters of
+            // zero reuse the last ones.
+            let mut cur_slide: i32 = 0;
+            let mut cur_portamento: u32 = 0;
+            let mut slide_up_memory = 0;
+            let mut slide_down_memory = 0;
+            let mut portamento_memory = 0;
+            let mut cur_arpeggio = (0, 0);
+            let mut arpeggio_rate_set = false;
+
             for pattern_order_index in 0..(xm.header.song_length as usize) {
                 let pattern_index = xm.header.pattern_order_table[pattern_order_index] as usize;
                 let pattern = &xm.patterns[pattern_index];
@@ -348,11 +423,53 @@ impl From<File> for intermediate::Module {
                         }
                     }
 
-                    if insert_note {
-                        if tick_counter != 0 {
-                            channel.push(intermediate::Command::Play(tick_counter));
-                        }
+                    // LatteFM ticks are XM rows, and XM slides on all but
+                    // the first tick of a row. Their units are 1/16 semitones
+                    // like in LatteFM.
+                    let slide_ticks = (xm.header.speed as u32).max(1) - 1;
+                    let parameter = note.effect_parameter.unwrap_or(0) as u32;
+                    let mut slide: i32 = 0;
+                    let mut portamento: u32 = 0;
+                    let mut arpeggio = (0, 0);
+                    match note.effect_type {
+                        Some(0x0) => arpeggio = (parameter >> 4, parameter & 0xF),
+                        Some(0x1) => {
+                            if parameter != 0 {
+                                slide_up_memory = parameter;
+                            }
+                            slide = (slide_up_memory * slide_ticks).min(255) as i32;
+                        },
+                        Some(0x2) => {
+                            if parameter != 0 {
+                                slide_down_memory = parameter;
+                            }
+                            slide = -((slide_down_memory * slide_ticks).min(255) as i32);
+                        },
+                        Some(0x3) => {
+                            if parameter != 0 {
+                                portamento_memory = parameter;
+                            }
+                            portamento = (portamento_memory * slide_ticks).min(255).max(1);
+                        },
+                        _ => ()
+                    }
+                    // Notes stop slides in LatteFM.
+                    let starts_note = insert_note && note_command != intermediate::RELEASE;
+                    let slide_after_note = if starts_note {0} else {cur_slide};
+                    let new_event = insert_note || slide != slide_after_note ||
+                        portamento != cur_portamento || arpeggio != cur_arpeggio;
+
+                    if new_event && tick_counter != 0 {
+                        channel.push(intermediate::Command::Play(tick_counter));
+                    }
+
+                    // Portamento must be set before the note it slides to.
+                    if portamento != cur_portamento {
+                        channel.push(intermediate::Command::Portamento(portamento));
+                        cur_portamento = portamento;
+                    }
 
+                    if insert_note {
                         if let Some(ref extra) = xm.instruments[cur_instrument].extra_header {
                             if note_command != intermediate::RELEASE && note_command != intermediate::PAUSE {
                                 let sample_index = extra.sample_number[note_command as usize] as usize;
@@ -364,9 +481,33 @@ impl From<File> for intermediate::Module {
 
                         channel.push(intermediate::Command::SetVolume(cur_volume));
                         channel.push(intermediate::Command::Note(note_command));
-                        tick_counter = 1;
+                        cur_slide = slide_after_note;
                     }
-                    else {
+
+                    if slide != cur_slide {
+                        channel.push(if slide < 0 {
+                            intermediate::Command::SlideDown((-slide) as u32)
+                        } else {
+                            intermediate::Command::SlideUp(slide as u32)
+                        });
+                        cur_slide = slide;
+                    }
+
+                    if arpeggio != cur_arpeggio {
+                        if !arpeggio_rate_set {
+                            // Arpeggio steps once per XM tick, always
+                            // cycling through note, +x and +y.
+                            channel.push(intermediate::Command::SetArpeggioRate(xm.header.speed as u32));
+                            channel.push(intermediate::Command::SetArpeggioSteps(3));
+                            arpeggio_rate_set = true;
+                        }
+                        channel.push(intermediate::Command::Arpeggio(arpeggio.0, arpeggio.1, 0));
+                        cur_arpeggio = arpeggio;
+                    }
+
+                    if new_event {
+                        tick_counter = 1;
+                    } else {
                         tick_counter += 1;
                     }
                 }
@@ -167,7 +167,24 @@ pub enum Command {
     SetInstrument(u8), // Changes instrument to the given index. Always set the instrument before starting the note it should play.
     SetVolume(u8), // Sets the general channel volume, applied on top of instrument volume.
     Play(u8), // Steps given of ticks ahead
+    Hold(u8), // Like Play, but the note sustains until KeyOff instead of releasing on its own.
+    Wait(u8), // Steps given of ticks ahead without starting a new note.
+    KeyOff, // Releases the current note. Usually followed by Wait to let the release ring out.
+    SlideUp(u8), // Slides pitch up by N/16 semitones per tick until the next Note. 0 stops.
+    SlideDown(u8), // Slides pitch down by N/16 semitones per tick until the next Note. 0 stops.
+    Portamento(u8), // Following notes slide to their pitch at N/16 semitones per tick. Use Wait after them to not restart the note. 0 turns it off and stops sliding.
+    SetGlide(u8), // Following notes glide to their pitch over N ticks, unless restarted by Play or Hold. 0 turns it off.
+    Arpeggio(u8, u8, u8), // Cycles the note through its pitch and these semitone offsets above it. Trailing zeros are left out, all zeros turns it off.
+    SetArpeggioRate(u8), // Arpeggio steps per tick, 1 by default.
+    SetArpeggioSteps(u8), // Fixed cycle length of following arpeggios, up to 4, zeros included. 0 trims trailing zeros, the default.
+    SetTempo(u16), // Sets the tick rate of all channels, in ticks per minute. Takes effect from the next tick.
     Jump(u8), // Jump to the given command index (used for looping)
     Repeat(u8), // Repeats the following command only N times, with the Nth time skipping the command. Useful with Jump.
+    LoopStart, // Marks the start of a loop, which may be nested in other loops.
+    LoopEnd(u8), // Plays the commands since the matching LoopStart N times in total.
+    Call(u8), // Jump to the given command index, continuing after this command at the next Return.
+    Return, // Return from the last Call. Does nothing outside a call.
+    SetPriority(u8), // Sound effects only take over channels with at most their priority, lowest first.
+    End, // Releases the current note and stops the channel. Also implied at the end of the stream.
     Pan(i8), // Stereo panning for the channel, 0 is center, -128 left and 127 right.
 }
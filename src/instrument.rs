@@ -1,63 +1,593 @@
-use crate::wave::{i16cos, i16square, i16triangle, i16saw, i16noise};
+use crate::wave::{i16cos, i16square, i16pulse, i16triangle, i16saw, i16noise, i16blsquare, i16blsaw, Wavetable};
 use crate::channel::note_names;
 
-#[repr(usize)]
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Waveform {
-    Sine = 0, Square, Triangle, Saw, Noise
+    Sine, Square, Triangle, Saw, Noise, Pulse,
+    // Slower, but alias much less on high notes. The band-limited feature
+    // makes Square and Saw behave like these.
+    BandLimitedSquare, BandLimitedSaw,
+    Table(u8) // Index to Tune::wavetables
 }
 
+impl Waveform {
+    // Index in WAVEGEN_TABLE, all wavetables share the same one.
+    pub fn index(self) -> usize {
+        match self {
+            Waveform::Sine => 0,
+            Waveform::Square => 1,
+            Waveform::Triangle => 2,
+            Waveform::Saw => 3,
+            Waveform::Noise => 4,
+            Waveform::Pulse => 5,
+            Waveform::BandLimitedSquare => 6,
+            Waveform::BandLimitedSaw => 7,
+            Waveform::Table(_) => 8
+        }
+    }
+}
+
+// $dt is the phase step of the current frame.
 macro_rules! oscillator {
-    (Waveform::Sine, $t:expr) => {i16cos($t)};
-    (Waveform::Square, $t:expr) => {i16square($t)};
-    (Waveform::Triangle, $t:expr) => {i16triangle($t)};
-    (Waveform::Saw, $t:expr) => {i16saw($t)};
-    (Waveform::Noise, $t:expr) => {i16noise($t)};
+    (Waveform::Sine, $t:expr, $duty:expr, $table:expr, $dt:expr) => {i16cos($t)};
+    (Waveform::Square, $t:expr, $duty:expr, $table:expr, $dt:expr) => {
+        if cfg!(feature = "band-limited") {i16blsquare($t, $dt)} else {i16square($t)}
+    };
+    (Waveform::Triangle, $t:expr, $duty:expr, $table:expr, $dt:expr) => {i16triangle($t)};
+    (Waveform::Saw, $t:expr, $duty:expr, $table:expr, $dt:expr) => {
+        if cfg!(feature = "band-limited") {i16blsaw($t, $dt)} else {i16saw($t)}
+    };
+    (Waveform::Noise, $t:expr, $duty:expr, $table:expr, $dt:expr) => {i16noise($t)};
+    (Waveform::Pulse, $t:expr, $duty:expr, $table:expr, $dt:expr) => {i16pulse($t, $duty)};
+    (Waveform::BandLimitedSquare, $t:expr, $duty:expr, $table:expr, $dt:expr) => {i16blsquare($t, $dt)};
+    (Waveform::BandLimitedSaw, $t:expr, $duty:expr, $table:expr, $dt:expr) => {i16blsaw($t, $dt)};
+    (Waveform::Table, $t:expr, $duty:expr, $table:expr, $dt:expr) => {$table.lookup($t)};
+}
+
+// Same as oscillator!, but for when the waveform is only known at runtime.
+#[inline]
+fn oscillate(waveform: Waveform, t: i16, duty: u16, wavetables: &[Wavetable], dt: i32) -> i16 {
+    match waveform {
+        Waveform::Sine => oscillator!(Waveform::Sine, t, duty, (), dt),
+        Waveform::Square => oscillator!(Waveform::Square, t, duty, (), dt),
+        Waveform::Triangle => oscillator!(Waveform::Triangle, t, duty, (), dt),
+        Waveform::Saw => oscillator!(Waveform::Saw, t, duty, (), dt),
+        Waveform::Noise => oscillator!(Waveform::Noise, t, duty, (), dt),
+        Waveform::Pulse => oscillator!(Waveform::Pulse, t, duty, (), dt),
+        Waveform::BandLimitedSquare => oscillator!(Waveform::BandLimitedSquare, t, duty, (), dt),
+        Waveform::BandLimitedSaw => oscillator!(Waveform::BandLimitedSaw, t, duty, (), dt),
+        Waveform::Table(index) => oscillator!(Waveform::Table, t, duty, wavetables[index as usize], dt),
+    }
+}
+
+// Placeholder for when the waveform isn't actually a wavetable.
+const NO_WAVETABLE: Wavetable = Wavetable::I8(&[0]);
+
+#[inline]
+fn get_wavetable<'a, 'b>(waveform: Waveform, wavetables: &'a [Wavetable<'b>]) -> &'a Wavetable<'b> {
+    match waveform {
+        Waveform::Table(index) => &wavetables[index as usize],
+        _ => &NO_WAVETABLE
+    }
+}
+
+// Operator connections of four-operator instruments. These are the same as the
+// eight algorithms of the OPN chips, with operators numbered from 0 instead of
+// 1. Operator 3 is always a carrier.
+#[repr(usize)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    Serial = 0,      // 0 -> 1 -> 2 -> 3
+    DualModulator,   // (0 + 1) -> 2 -> 3
+    LongBranch,      // (0 + (1 -> 2)) -> 3
+    ShortBranch,     // ((0 -> 1) + 2) -> 3
+    TwoPairs,        // (0 -> 1) + (2 -> 3)
+    SharedModulator, // (0 -> 1) + (0 -> 2) + (0 -> 3)
+    PairAndTwo,      // (0 -> 1) + 2 + 3
+    Parallel,        // 0 + 1 + 2 + 3
+}
+
+struct Routing {
+    modulators: [u8; 4], // Bitmask of the operators modulating each operator.
+    carriers: u8, // Bitmask of the operators that are heard.
+}
+
+const ALGORITHM_ROUTING: [Routing; 8] = [
+    Routing { modulators: [0, 0b0001, 0b0010, 0b0100], carriers: 0b1000 },
+    Routing { modulators: [0, 0, 0b0011, 0b0100], carriers: 0b1000 },
+    Routing { modulators: [0, 0, 0b0010, 0b0101], carriers: 0b1000 },
+    Routing { modulators: [0, 0b0001, 0, 0b0110], carriers: 0b1000 },
+    Routing { modulators: [0, 0b0001, 0, 0b0100], carriers: 0b1010 },
+    Routing { modulators: [0, 0b0001, 0b0001, 0b0001], carriers: 0b1110 },
+    Routing { modulators: [0, 0b0001, 0, 0], carriers: 0b1110 },
+    Routing { modulators: [0, 0, 0, 0], carriers: 0b1111 },
+];
+
+#[derive(Clone, Copy)]
+pub struct Operator {
+    pub waveform: Waveform,
+    // 16-bit fixed point. Modulation amount for modulators, output level for
+    // carriers. The levels of all carriers should add up to at most 1.
+    pub level: u16,
+    pub mul: u16,
+    pub div: u16,
+}
+
+#[derive(Clone, Copy)]
+pub struct FourOp {
+    pub algorithm: Algorithm,
+    pub operators: [Operator; 4],
+}
+
+#[derive(Clone, Copy)]
+pub struct Sample<'a> {
+    pub data: &'a [i8],
+    pub rate: u32, // Playback rate in Hz when the note is C4.
+    pub loop_start: u32, // In samples.
+    pub loop_length: u32, // In samples, 0 if the sample doesn't loop.
+}
+
+#[derive(Clone, Copy)]
+pub enum InstrumentKind<'a> {
+    TwoOp, // Uses the carrier and modulator fields of the instrument.
+    FourOp(FourOp), // Ignores the carrier and modulator fields.
+    Sample(Sample<'a>), // Ignores the carrier and modulator fields.
+}
+
+// An envelope that isn't the main amplitude envelope of an instrument. Its
+// levels are relative to whatever it scales.
+#[derive(Clone, Copy)]
+pub struct ADSR {
+    pub attack: u16,  // 12-bit fixed point, in seconds.
+    pub decay: u16,   // 12-bit fixed point, in seconds.
+    pub sustain: u16, // 16-bit fixed point (sustain level)
+    pub release: u16, // 12-bit fixed point, in seconds
+    pub curves: [Curve; 3], // Of attack, decay and release.
+}
+
+// An envelope made of linear segments between arbitrary points, like the ones
+// in trackers.
+#[derive(Clone, Copy)]
+pub struct Envelope<'a> {
+    // Time since the start of the note (12-bit fixed point, in seconds) and
+    // level (16-bit fixed point) of each point.
+    pub points: &'a [(u16, u16)],
+    pub sustain: Option<u8>, // Point to stay at until key-off.
+    pub loop_points: Option<(u8, u8)>, // Reaching the second point jumps back to the first.
+}
+
+// Shape of an envelope stage.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Curve {
+    Linear,
+    // Changes slowly at low levels and fast at high levels, so that rising
+    // stages speed up and falling stages slow down. Sounds natural for decays
+    // and releases.
+    Exponential,
+    // The opposite, rising stages slow down and falling stages speed up.
+    Logarithmic,
+}
+
+// Curved stages are split into this many linear segments, so that the
+// wavegens only ever have to add a step.
+const CURVE_SEGMENTS: usize = 16;
+
+// Progress through a rising exponential stage at the end of each segment,
+// 16-bit fixed point. (e^5x - 1) / (e^5 - 1)
+const EXPONENTIAL_CURVE: [i64; CURVE_SEGMENTS+1] = [
+    0, 163, 386, 691, 1107, 1676, 2454, 3518, 4971,
+    6958, 9674, 13386, 18459, 25394, 34872, 47828, 65536
+];
+
+#[derive(Clone, Copy)]
+pub struct LFO {
+    pub waveform: Waveform,
+    pub rate: u16,  // 8-bit fixed point, in Hz.
+    pub depth: u16, // 16-bit fixed point
+    pub fade_in: u16, // 12-bit fixed point, in seconds. Depth rises from 0 over this time after note-on.
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct LFOState {
+    pub phase: u32,
+    pub frames: i32, // Since the start of the note.
+}
+
+impl LFOState {
+    // Depth at the current point of the fade-in (16-bit fixed point).
+    pub fn depth(&self, lfo: &LFO, samplerate: i32) -> i32 {
+        let fade_in_frames = ((lfo.fade_in as i32)*samplerate) >> 12;
+        if self.frames < fade_in_frames {
+            ((lfo.depth as i64) * (self.frames as i64) / (fade_in_frames as i64)) as i32
+        } else {
+            lfo.depth as i32
+        }
+    }
+
+    // Returns the current output scaled by depth (16-bit fixed point), then
+    // advances the LFO by the given number of frames.
+    pub fn advance(
+        &mut self,
+        lfo: &LFO,
+        samplerate: i32,
+        frames: i32,
+        wavetables: &[Wavetable]
+    ) -> i32 {
+        let wave = oscillate(lfo.waveform, (self.phase >> 16) as i16, 1<<15, wavetables, 0) as i32;
+        let value = (wave * self.depth(lfo, samplerate)) >> 15;
+
+        let phase_step = ((lfo.rate as u64) << 24) / (samplerate as u64);
+        self.phase = self.phase.wrapping_add((phase_step * (frames as u64)) as u32);
+        self.frames = self.frames.saturating_add(frames);
+        value
+    }
+}
+
+// A sequence of values stepped through once per tick, like the instrument
+// macros of FamiTracker.
+#[derive(Clone, Copy)]
+pub struct Macro<'a> {
+    pub values: &'a [i16],
+    // Reaching the end jumps back here. If the loop point is before the release
+    // point, it's only used until the note is released.
+    pub loop_point: Option<u8>,
+    // The sequence stays here (or loops back) until the note is released.
+    pub release_point: Option<u8>,
+}
+
+#[derive(Clone, Copy)]
+pub struct Macros<'a> {
+    pub volume: Option<Macro<'a>>, // 8-bit fixed point gain, at most 1.
+    pub arpeggio: Option<Macro<'a>>, // Semitones added to the note.
+    pub pitch: Option<Macro<'a>>, // 1/16 semitones added to the pitch every tick.
+    pub duty: Option<Macro<'a>>, // 8-bit fixed point, replaces the duty of the instrument.
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct MacroState {
+    pub position: usize,
+}
+
+impl MacroState {
+    // Returns the current value, then moves on to the next one.
+    pub fn advance(&mut self, sequence: &Macro, released: bool) -> i32 {
+        let len = sequence.values.len();
+        if len == 0 {
+            return 0;
+        }
+        let position = self.position.min(len - 1);
+        let value = sequence.values[position] as i32;
+        self.position = if !released && sequence.release_point == Some(position as u8) {
+            match sequence.loop_point {
+                Some(start) if (start as usize) <= position => start as usize,
+                _ => position
+            }
+        } else if position + 1 < len {
+            position + 1
+        } else {
+            match (sequence.loop_point, sequence.release_point) {
+                (Some(start), Some(release)) if start > release => start as usize,
+                (Some(start), None) => start as usize,
+                _ => position
+            }
+        };
+        value
+    }
 }
 
 #[derive(Clone, Copy)]
 pub struct ADSRStep {
-    pub frames_left: i32,
+    pub frames_left: i32, // Of the current segment if curved.
     pub amplitude_step: (i32, i32),
+    pub curve: Curve,
+    pub length: i32, // In frames
+    pub change: (i32, i32), // Total change in amplitude over the stage.
 }
 
+impl ADSRStep {
+    pub fn new(length: i32, change: (i32, i32), curve: Curve) -> ADSRStep {
+        let mut amplitude_step = change;
+        if length > 0 {
+            amplitude_step.0 /= length;
+            amplitude_step.1 /= length;
+        }
+        ADSRStep {
+            frames_left: length,
+            amplitude_step,
+            curve,
+            length,
+            change
+        }
+    }
+}
+
+// Position in an Envelope. The current segment is kept in the first stage of
+// ADSRState.
 #[derive(Clone, Copy)]
-pub struct ADSRState {
+pub struct PointState<'a> {
+    pub envelope: Envelope<'a>,
+    pub point: usize, // The segment being walked starts from this point.
+    pub gain: (i32, i32), // 24-bit fixed point amplitude at level 1.
+    pub samplerate: i32,
+    pub released: bool,
+}
+
+impl<'a> PointState<'a> {
+    fn level(&self, point: usize) -> (i32, i32) {
+        let level = self.envelope.points[point].1 as i64;
+        (
+            ((level * self.gain.0 as i64) >> 16) as i32,
+            ((level * self.gain.1 as i64) >> 16) as i32
+        )
+    }
+}
+
+const RELEASE_STAGE: usize = 3;
+const OFF_STAGE: usize = 4;
+
+// Stages are attack, decay, sustain, release and off. The off stage lasts
+// forever, so a released note can keep running silently.
+#[derive(Clone, Copy)]
+pub struct ADSRState<'a> {
     pub cur_stage: usize,
-    pub stages: [ADSRStep; 4]
+    pub stages: [ADSRStep; 5],
+    pub cur_segment: usize, // Of a curved stage
+    pub stage_start: (i32, i32), // Amplitude at the start of a curved stage
+    pub points: Option<PointState<'a>>, // Replaces the stages if set.
 }
 
-impl Default for ADSRState {
-    fn default() -> ADSRState {
+impl<'a> Default for ADSRState<'a> {
+    fn default() -> ADSRState<'a> {
+        let mut stages = [ADSRStep::new(0, (0, 0), Curve::Linear); 5];
+        stages[OFF_STAGE].frames_left = i32::MAX;
         ADSRState {
             cur_stage: 0,
-            stages: [ADSRStep {frames_left: 0, amplitude_step: (0, 0)}; 4]
+            stages,
+            cur_segment: 0,
+            stage_start: (0, 0),
+            points: None
         }
     }
 }
 
-impl ADSRState {
+impl<'a> ADSRState<'a> {
     pub fn init_stage_amplitude(&mut self, amplitude: &mut (i32, i32)) {
-        while self.cur_stage < self.stages.len() && self.stages[self.cur_stage].frames_left == 0 {
+        if self.points.is_some() {
+            self.enter_point(amplitude);
+            return;
+        }
+        while self.cur_stage < self.stages.len() && self.stages[self.cur_stage].frames_left <= 0 {
             amplitude.0 += self.stages[self.cur_stage].amplitude_step.0;
             amplitude.1 += self.stages[self.cur_stage].amplitude_step.1;
             self.cur_stage += 1;
         }
+        // Rounding errors in the steps may leave something behind.
+        if self.cur_stage == OFF_STAGE {
+            *amplitude = (0, 0);
+        } else if self.cur_stage < self.stages.len() && self.stages[self.cur_stage].curve != Curve::Linear {
+            self.cur_segment = 0;
+            self.stage_start = *amplitude;
+            self.init_segment(amplitude);
+        }
+    }
+
+    // Called when the current stage or segment runs out.
+    pub fn next_segment(&mut self, amplitude: &mut (i32, i32)) {
+        if let Some(ref mut points) = self.points {
+            points.point += 1;
+            self.enter_point(amplitude);
+        } else if self.stages[self.cur_stage].curve != Curve::Linear && self.cur_segment + 1 < CURVE_SEGMENTS {
+            self.cur_segment += 1;
+            self.init_segment(amplitude);
+        } else {
+            self.cur_stage += 1;
+            self.init_stage_amplitude(amplitude);
+        }
+    }
+
+    // Sets up a linear step towards the end of the current segment, skipping
+    // segments that are too short to have any frames.
+    fn init_segment(&mut self, amplitude: &mut (i32, i32)) {
+        let stage = &mut self.stages[self.cur_stage];
+        let rising = stage.change.0 + stage.change.1 > 0;
+        let convex = (stage.curve == Curve::Exponential) == rising;
+        let progress = |segment: usize| if convex {
+            EXPONENTIAL_CURVE[segment]
+        } else {
+            (1<<16) - EXPONENTIAL_CURVE[CURVE_SEGMENTS - segment]
+        };
+        while self.cur_segment < CURVE_SEGMENTS {
+            let segment = self.cur_segment;
+            let length = stage.length as i64;
+            let frames = (length * (segment as i64 + 1) / CURVE_SEGMENTS as i64
+                - length * segment as i64 / CURVE_SEGMENTS as i64) as i32;
+            let target = (
+                self.stage_start.0 + ((stage.change.0 as i64 * progress(segment+1)) >> 16) as i32,
+                self.stage_start.1 + ((stage.change.1 as i64 * progress(segment+1)) >> 16) as i32
+            );
+            if frames > 0 {
+                stage.frames_left = frames;
+                stage.amplitude_step = (
+                    (target.0 - amplitude.0) / frames,
+                    (target.1 - amplitude.1) / frames
+                );
+                return;
+            }
+            *amplitude = target;
+            self.cur_segment += 1;
+        }
+        // Only possible if the whole stage has no length, which
+        // init_stage_amplitude skips anyway.
+        stage.frames_left = 0;
+    }
+
+    // Starts the segment from the current point of an Envelope. Handles
+    // sustain and loop points, and skips segments that take no time.
+    fn enter_point(&mut self, amplitude: &mut (i32, i32)) {
+        let points = match self.points {
+            Some(ref mut points) => points,
+            None => return
+        };
+        let envelope = points.envelope;
+        // A loop of segments without any frames would never end.
+        let mut looped = false;
+        loop {
+            let point = points.point;
+            if point >= envelope.points.len() {
+                // Holds the last level forever.
+                if point == 0 {
+                    *amplitude = (0, 0);
+                }
+                self.stages[0] = ADSRStep::new(i32::MAX, (0, 0), Curve::Linear);
+                return;
+            }
+            // Snap to the point in case the steps didn't add up exactly.
+            *amplitude = points.level(point);
+            if !points.released && envelope.sustain == Some(point as u8) {
+                self.stages[0] = ADSRStep::new(i32::MAX, (0, 0), Curve::Linear);
+                return;
+            }
+            // Released notes leave the loop, so that they can end.
+            if let (Some((start, end)), false) = (envelope.loop_points, points.released) {
+                if point == end as usize && start < end {
+                    if looped {
+                        self.stages[0] = ADSRStep::new(i32::MAX, (0, 0), Curve::Linear);
+                        return;
+                    }
+                    looped = true;
+                    points.point = start as usize;
+                    continue;
+                }
+            }
+            if point + 1 >= envelope.points.len() {
+                points.point = envelope.points.len();
+                continue;
+            }
+            let time = envelope.points[point+1].0 as i32 - envelope.points[point].0 as i32;
+            let frames = (time * points.samplerate) >> 12;
+            if frames > 0 {
+                let target = points.level(point+1);
+                self.stages[0] = ADSRStep::new(
+                    frames,
+                    (target.0 - amplitude.0, target.1 - amplitude.1),
+                    Curve::Linear
+                );
+                return;
+            }
+            points.point += 1;
+        }
+    }
+
+    // True once a released envelope has run out, or a point envelope has
+    // reached its last point.
+    pub fn is_finished(&self) -> bool {
+        match self.points {
+            Some(ref points) => points.point >= points.envelope.points.len(),
+            None => self.cur_stage >= OFF_STAGE
+        }
+    }
+
+    // Replaces the envelope with a linear ramp from the current amplitude to
+    // zero, after which it is finished.
+    pub fn fade_out(&mut self, frames: i32, amplitude: &mut (i32, i32)) {
+        self.points = None;
+        self.cur_stage = RELEASE_STAGE;
+        self.stages[RELEASE_STAGE] = ADSRStep::new(frames, (-amplitude.0, -amplitude.1), Curve::Linear);
+        self.init_stage_amplitude(amplitude);
+    }
+
+    // Jumps to the release stage, ramping down from the current amplitude over
+    // the given number of frames. Does nothing if already releasing. Point
+    // envelopes just move on from their sustain point.
+    pub fn release(&mut self, frames: i32, amplitude: &mut (i32, i32)) {
+        if let Some(ref mut points) = self.points {
+            if !points.released {
+                points.released = true;
+                if points.envelope.sustain == Some(points.point as u8) {
+                    self.enter_point(amplitude);
+                }
+            }
+            return;
+        }
+        if self.cur_stage >= RELEASE_STAGE {
+            return;
+        }
+        self.cur_stage = RELEASE_STAGE;
+        let curve = self.stages[RELEASE_STAGE].curve;
+        self.stages[RELEASE_STAGE] = ADSRStep::new(frames, (-amplitude.0, -amplitude.1), curve);
+        self.init_stage_amplitude(amplitude);
+    }
+}
+
+// Operator slots used by two-operator instruments in Voice.
+pub const MODULATOR: usize = 0;
+pub const CARRIER: usize = 1;
+
+// Synthesis state of the note that is currently playing on a channel.
+#[derive(Clone, Copy)]
+pub struct Voice<'a> {
+    pub adsr: ADSRState<'a>,
+    pub amplitude: (i32, i32), // 24-bit fixed point (to avoid some rounding stupidity)
+    pub modulator_adsr: ADSRState<'a>,
+    pub modulation: (i32, i32), // 24-bit fixed point, only the first one is used.
+    pub steps: [i32; 4],
+    pub phases: [i32; 4],
+    pub feedback: (i32, i32), // Last two outputs of the self-modulating operator.
+    pub tremolo: i32, // 15-bit fixed point gain
+    pub tremolo_step: i32,
+    pub duty: u16, // Of the pulse waveform, 16-bit fixed point
+    pub sample_position: u64, // 16-bit fixed point, steps[0] is added to it.
+}
+
+impl<'a> Default for Voice<'a> {
+    fn default() -> Voice<'a> {
+        Voice {
+            adsr: Default::default(),
+            amplitude: (0, 0),
+            modulator_adsr: Default::default(),
+            modulation: (0, 0),
+            steps: [0; 4],
+            phases: [0; 4],
+            feedback: (0, 0),
+            tremolo: 1<<15,
+            tremolo_step: 0,
+            duty: 1<<15,
+            sample_position: 0
+        }
     }
 }
 
-pub struct Instrument {
+pub struct Instrument<'a> {
     pub carrier_waveform: Waveform,
     pub amplitude: u16, // 16-bit fixed point
     pub attack: u16,  // 12-bit fixed point, in seconds.
     pub decay: u16,   // 12-bit fixed point, in seconds.
     pub sustain: u16, // 16-bit fixed point (sustain amplitude)
     pub release: u16, // 12-bit fixed point, in seconds
+    pub curves: [Curve; 3], // Of attack, decay and release.
+    // Replaces the ADSR envelope above if set. Levels are relative to amplitude.
+    pub envelope: Option<Envelope<'a>>,
     pub modulator_waveform: Waveform,
     pub modulator_amplitude: u16, // 16-bit fixed point
     pub modulator_mul: u16,
     pub modulator_div: u16,
     pub modulator_phase: u16,
+    // Scales the modulation amount over the note. It's constant when None.
+    pub modulator_envelope: Option<ADSR>,
+    // Self-modulation of the modulator (operator 0 in four-operator
+    // instruments), 0 is off and 7 is the maximum. Same as on the OPN chips.
+    pub feedback: u8,
+    // Depth is relative to the frequency of the note.
+    pub vibrato: Option<LFO>,
+    // Depth is the fraction of the amplitude that the LFO takes away at most.
+    pub tremolo: Option<LFO>,
+    // The fraction of the cycle that the pulse waveform is high, 16-bit fixed
+    // point. Shared by all operators.
+    pub duty: u16,
+    // Modulates the duty, depth is in the same units.
+    pub pwm: Option<LFO>,
+    pub macros: Option<Macros<'a>>,
+    pub kind: InstrumentKind<'a>,
 }
 
 
@@ -78,62 +608,111 @@ const NOTE_FREQ_LOOKUP: [u32; 12] = [
     517874176
 ];
 
+// Phase offset multipliers for feedback levels, the last one is 4 pi. Applied to
+// the sum of the last two outputs and shifted down by 6.
+const FEEDBACK_MUL: [i32; 8] = [0, 2, 4, 8, 16, 32, 64, 128];
+
+// Wavegens add their output to a wide mix buffer. Full scale is 1<<30 before
+// being shifted down by `shift`, so a shift of 23 gives 8-bit output and 15
+// gives 16-bit output.
 pub type Wavegen = fn(
     instr: &Instrument,
-    adsr: &mut ADSRState,
-    amplitude: &mut (i32, i32),
-    carrier_step: i32,
-    carrier_phase: &mut i32,
-    modulator_step: i32,
-    modulator_phase: &mut i32,
-    out: &mut [i8]
+    wavetables: &[Wavetable],
+    voice: &mut Voice,
+    out: &mut [i32],
+    shift: u32
 );
 
+// Runs the ADSR envelopes over the output buffer. `oscillators` is called once
+// per frame with the phases and modulation scale (15-bit fixed point) and must
+// return the next sample of the carrier.
+#[inline(always)]
+fn envelope_loop<F: FnMut(&mut [i32; 4], i32) -> i32>(
+    voice: &mut Voice,
+    out: &mut [i32],
+    shift: u32,
+    mut oscillators: F
+) {
+    let mut frames_left: i32 = (out.len()>>1) as i32;
+    let mut start_frame: usize = 0;
+
+    while frames_left > 0 {
+        let stage = &mut voice.adsr.stages[voice.adsr.cur_stage];
+        let modulator_stage = &mut voice.modulator_adsr.stages[voice.modulator_adsr.cur_stage];
+        let mut step_frames = if frames_left < stage.frames_left {
+            frames_left
+        } else {
+            stage.frames_left
+        };
+        if modulator_stage.frames_left < step_frames {
+            step_frames = modulator_stage.frames_left;
+        }
+
+        let end_frame = start_frame+(step_frames as usize);
+        for i in start_frame..end_frame {
+            let carrier = (oscillators(&mut voice.phases, voice.modulation.0 >> 9) * voice.tremolo) >> 15;
+            out[i*2] += (carrier*(voice.amplitude.0 >> 9)) >> shift;
+            out[i*2+1] += (carrier*(voice.amplitude.1 >> 9)) >> shift;
+            voice.amplitude.0 += stage.amplitude_step.0;
+            voice.amplitude.1 += stage.amplitude_step.1;
+            voice.modulation.0 += modulator_stage.amplitude_step.0;
+            voice.tremolo += voice.tremolo_step;
+        }
+
+        start_frame = end_frame;
+        frames_left -= step_frames;
+        stage.frames_left -= step_frames;
+        modulator_stage.frames_left -= step_frames;
+        if stage.frames_left <= 0 {
+            voice.adsr.next_segment(&mut voice.amplitude);
+        }
+        if modulator_stage.frames_left <= 0 {
+            voice.modulator_adsr.next_segment(&mut voice.modulation);
+        }
+    }
+}
+
 macro_rules! wavegen_template {
     ($carrier_waveform:ident, $modulator_waveform:ident) => {
         {
+            // Duty and wavetables are only used by some waveforms.
+            #[allow(unused_variables)]
             fn local_wavegen(
                 instr: &Instrument,
-                adsr: &mut ADSRState,
-                amplitude: &mut (i32, i32),
-                carrier_step: i32,
-                carrier_phase: &mut i32,
-                modulator_step: i32,
-                modulator_phase: &mut i32,
-                out: &mut [i8]
+                wavetables: &[Wavetable],
+                voice: &mut Voice,
+                out: &mut [i32],
+                shift: u32
             ){
-                let mut frames_left: i32 = (out.len()>>1) as i32;
-                let mut start_frame: usize = 0;
-
-                while frames_left > 0 {
-                    let stage = &mut adsr.stages[adsr.cur_stage];
-                    let step_frames = if frames_left < stage.frames_left {
-                        frames_left
-                    } else {
-                        stage.frames_left
-                    };
-
-                    let end_frame = start_frame+(step_frames as usize);
-                    for i in start_frame..end_frame {
-                        let modulator = oscillator!(Waveform::$modulator_waveform, *modulator_phase as i16);
-                        let mod_value = ((modulator as i32) * (instr.modulator_amplitude as i32)) >> 20; // 12-bit fixed point
-                        let carrier = oscillator!(Waveform::$carrier_waveform, *carrier_phase as i16) as i32;
-                        out[i*2] += ((carrier*(amplitude.0 >> 9)) >> 23) as i8;
-                        out[i*2+1] += ((carrier*(amplitude.1 >> 9)) >> 23) as i8;
-                        *carrier_phase += carrier_step * (mod_value + (1<<11)) >> 11;
-                        *modulator_phase += modulator_step;
-                        amplitude.0 += stage.amplitude_step.0;
-                        amplitude.1 += stage.amplitude_step.1;
-                    }
-
-                    start_frame = end_frame;
-                    frames_left -= step_frames;
-                    stage.frames_left -= step_frames;
-                    if(stage.frames_left == 0) {
-                        adsr.cur_stage += 1;
-                        adsr.init_stage_amplitude(amplitude);
-                    }
-                }
+                let steps = voice.steps;
+                let duty = voice.duty;
+                let feedback_mul = FEEDBACK_MUL[(instr.feedback & 7) as usize];
+                let mut feedback = voice.feedback;
+                envelope_loop(voice, out, shift, |phases, modulation| {
+                    let feedback_offset = ((feedback.0 + feedback.1) * feedback_mul) >> 6;
+                    let modulator = oscillator!(
+                        Waveform::$modulator_waveform,
+                        (phases[MODULATOR] + feedback_offset) as i16,
+                        duty,
+                        get_wavetable(instr.modulator_waveform, wavetables),
+                        steps[MODULATOR]
+                    );
+                    feedback = (feedback.1, modulator as i32);
+                    let modulator_amplitude = ((instr.modulator_amplitude as i32) * modulation) >> 15;
+                    let mod_value = ((modulator as i32) * modulator_amplitude) >> 20; // 12-bit fixed point
+                    let carrier_step = (steps[CARRIER] * (mod_value + (1<<11))) >> 11;
+                    let carrier = oscillator!(
+                        Waveform::$carrier_waveform,
+                        phases[CARRIER] as i16,
+                        duty,
+                        get_wavetable(instr.carrier_waveform, wavetables),
+                        carrier_step
+                    ) as i32;
+                    phases[CARRIER] += carrier_step;
+                    phases[MODULATOR] += steps[MODULATOR];
+                    carrier
+                });
+                voice.feedback = feedback;
             }
             local_wavegen
         }
@@ -147,88 +726,350 @@ macro_rules! wavegen_set {
         wavegen_template!($carrier_waveform, Triangle),
         wavegen_template!($carrier_waveform, Saw),
         wavegen_template!($carrier_waveform, Noise),
+        wavegen_template!($carrier_waveform, Pulse),
+        wavegen_template!($carrier_waveform, BandLimitedSquare),
+        wavegen_template!($carrier_waveform, BandLimitedSaw),
+        wavegen_template!($carrier_waveform, Table),
     ]};
 }
 
-pub const WAVEGEN_TABLE: [[Wavegen; 5]; 5] = [
+pub const WAVEGEN_TABLE: [[Wavegen; 9]; 9] = [
     wavegen_set!(Sine),
     wavegen_set!(Square),
     wavegen_set!(Triangle),
     wavegen_set!(Saw),
-    wavegen_set!(Noise)
+    wavegen_set!(Noise),
+    wavegen_set!(Pulse),
+    wavegen_set!(BandLimitedSquare),
+    wavegen_set!(BandLimitedSaw),
+    wavegen_set!(Table)
 ];
 
-impl Instrument {
+// There are far too many waveform combinations to specialize four-operator
+// wavegens for all of them, so only the algorithm is baked in.
+macro_rules! four_op_wavegen_template {
+    ($algorithm:expr) => {
+        {
+            fn local_wavegen(
+                instr: &Instrument,
+                wavetables: &[Wavetable],
+                voice: &mut Voice,
+                out: &mut [i32],
+                shift: u32
+            ){
+                let operators = match instr.kind {
+                    InstrumentKind::FourOp(ref four_op) => &four_op.operators,
+                    _ => return
+                };
+                let routing = &ALGORITHM_ROUTING[$algorithm as usize];
+                let steps = voice.steps;
+                let duty = voice.duty;
+                let feedback_mul = FEEDBACK_MUL[(instr.feedback & 7) as usize];
+                let mut feedback = voice.feedback;
+                envelope_loop(voice, out, shift, |phases, modulation| {
+                    let mut mod_values = [0i32; 4];
+                    let mut carrier = 0;
+                    for i in 0..4 {
+                        let mut mod_value = 0;
+                        for j in 0..i {
+                            if routing.modulators[i] & (1<<j) != 0 {
+                                mod_value += mod_values[j];
+                            }
+                        }
+                        let op = &operators[i];
+                        let step = (steps[i] * (mod_value + (1<<11))) >> 11;
+                        let value = if i == 0 {
+                            let feedback_offset = ((feedback.0 + feedback.1) * feedback_mul) >> 6;
+                            let value = oscillate(op.waveform, (phases[0] + feedback_offset) as i16, duty, wavetables, step) as i32;
+                            feedback = (feedback.1, value);
+                            value
+                        } else {
+                            oscillate(op.waveform, phases[i] as i16, duty, wavetables, step) as i32
+                        };
+                        if routing.carriers & (1<<i) != 0 {
+                            carrier += (value * (op.level as i32)) >> 16;
+                        } else {
+                            let level = ((op.level as i32) * modulation) >> 15;
+                            mod_values[i] = (value * level) >> 20; // 12-bit fixed point
+                        }
+                        phases[i] += step;
+                    }
+                    // Clamp in case the carrier levels add up to more than 1.
+                    if carrier > 0x7FFF {
+                        0x7FFF
+                    } else if carrier < -0x7FFF {
+                        -0x7FFF
+                    } else {
+                        carrier
+                    }
+                });
+                voice.feedback = feedback;
+            }
+            local_wavegen
+        }
+    };
+}
+
+pub const FOUR_OP_WAVEGEN_TABLE: [Wavegen; 8] = [
+    four_op_wavegen_template!(Algorithm::Serial),
+    four_op_wavegen_template!(Algorithm::DualModulator),
+    four_op_wavegen_template!(Algorithm::LongBranch),
+    four_op_wavegen_template!(Algorithm::ShortBranch),
+    four_op_wavegen_template!(Algorithm::TwoPairs),
+    four_op_wavegen_template!(Algorithm::SharedModulator),
+    four_op_wavegen_template!(Algorithm::PairAndTwo),
+    four_op_wavegen_template!(Algorithm::Parallel),
+];
+
+fn sample_wavegen(
+    instr: &Instrument,
+    _wavetables: &[Wavetable],
+    voice: &mut Voice,
+    out: &mut [i32],
+    shift: u32
+){
+    let sample = match instr.kind {
+        InstrumentKind::Sample(ref sample) => sample,
+        _ => return
+    };
+    let step = voice.steps[0] as u64;
+    let end = (sample.data.len() as u64) << 16;
+    let loop_end = ((sample.loop_start + sample.loop_length) as u64) << 16;
+    let loop_length = (sample.loop_length as u64) << 16;
+    let mut position = voice.sample_position;
+    envelope_loop(voice, out, shift, |_, _| {
+        while loop_length != 0 && position >= loop_end {
+            position -= loop_length;
+        }
+        if position < end {
+            let value = (sample.data[(position >> 16) as usize] as i32) << 8;
+            position += step;
+            value
+        } else {
+            0
+        }
+    });
+    voice.sample_position = position;
+}
+
+// C4 in the same units as NOTE_FREQ_LOOKUP.
+const C4_FREQ: i64 = (NOTE_FREQ_LOOKUP[0] >> 4) as i64;
+
+// Frequency of a note in the same units as NOTE_FREQ_LOOKUP. The pitch must
+// be at most B8.
+fn note_frequency(pitch: i32) -> i64 {
+    let max_note = note_names::B8 as i32;
+    let octave = (max_note - pitch)/12;
+    let lookup_index = octave * 12 + pitch - max_note + 11;
+    (NOTE_FREQ_LOOKUP[lookup_index as usize] >> octave) as i64
+}
+
+// Divides the given frequency by the samplerate, rounding to nearest.
+fn frequency_to_step(frequency: i64, samplerate: i32) -> i32 {
+    let samplerate = samplerate as i64;
+    ((frequency-(samplerate+1)/2)/samplerate+1) as i32
+}
+
+impl<'a> Instrument<'a> {
+    // A plain sine without modulation or effects. Instruments can start from
+    // it with `..Instrument::DEFAULT` and only list what they change.
+    pub const DEFAULT: Instrument<'a> = Instrument {
+        carrier_waveform: Waveform::Sine,
+        amplitude: u16::MAX/4,
+        attack: 0,
+        decay: 0,
+        sustain: u16::MAX/4,
+        release: 0,
+        curves: [Curve::Linear; 3],
+        envelope: None,
+        modulator_waveform: Waveform::Sine,
+        modulator_amplitude: 0,
+        modulator_mul: 1,
+        modulator_div: 1,
+        modulator_phase: 0,
+        modulator_envelope: None,
+        feedback: 0,
+        vibrato: None,
+        tremolo: None,
+        duty: 1<<15,
+        pwm: None,
+        macros: None,
+        kind: InstrumentKind::TwoOp
+    };
+
     pub fn get_wavegen(&self) -> Wavegen {
-        // All this song and dance is just to avoid a couple of match statements
-        // in the tight loop in wavegen :D
-        WAVEGEN_TABLE[self.carrier_waveform as usize][self.modulator_waveform as usize]
+        match self.kind {
+            // All this song and dance is just to avoid a couple of match
+            // statements in the tight loop in wavegen :D
+            InstrumentKind::TwoOp => WAVEGEN_TABLE[self.carrier_waveform.index()][self.modulator_waveform.index()],
+            InstrumentKind::FourOp(ref four_op) => FOUR_OP_WAVEGEN_TABLE[four_op.algorithm as usize],
+            InstrumentKind::Sample(_) => sample_wavegen
+        }
     }
 
     pub fn get_timer_steps(
         &self,
         samplerate: i32,
         pitch: i32,
-        carrier_step: &mut i32,
-        modulator_step: &mut i32
+        steps: &mut [i32; 4]
+    ) {
+        self.get_fine_timer_steps(samplerate, pitch << 16, steps);
+    }
+
+    // Same as get_timer_steps, but the pitch is in 16-bit fixed point so that
+    // it can be between notes. Frequency is interpolated linearly between
+    // semitones.
+    pub fn get_fine_timer_steps(
+        &self,
+        samplerate: i32,
+        pitch: i32,
+        steps: &mut [i32; 4]
     ) {
         let max_note = note_names::B8 as i32;
-        if pitch <= max_note {
-            let octave = (max_note - pitch)/12;
-            let lookup_index = octave * 12 + pitch - max_note + 11;
-            let carrier_base_steps = (NOTE_FREQ_LOOKUP[lookup_index as usize] >> octave) as i32;
-            let modulator_base_steps = carrier_base_steps * (self.modulator_mul as i32) / (self.modulator_div as i32);
-            // Essentially just divide by samplerate, but round to nearest too.
-            *carrier_step = (carrier_base_steps-(samplerate+1)/2)/samplerate+1;
-            *modulator_step = (modulator_base_steps-(samplerate+1)/2)/samplerate+1;
-        } else {
-            // Dumbest way ever for marking pauses...
-            *carrier_step = 0;
-            *modulator_step = 0;
+        *steps = [0; 4];
+        if pitch <= max_note << 16 {
+            let mut base_steps = note_frequency(pitch >> 16);
+            let fraction = (pitch & 0xFFFF) as i64;
+            if fraction != 0 {
+                let next_steps = note_frequency((pitch >> 16) + 1);
+                base_steps += ((next_steps - base_steps) * fraction) >> 16;
+            }
+            match self.kind {
+                InstrumentKind::TwoOp => {
+                    let modulator_base_steps = base_steps * (self.modulator_mul as i64) / (self.modulator_div as i64);
+                    steps[CARRIER] = frequency_to_step(base_steps, samplerate);
+                    steps[MODULATOR] = frequency_to_step(modulator_base_steps, samplerate);
+                },
+                InstrumentKind::FourOp(ref four_op) => {
+                    for (step, op) in steps.iter_mut().zip(four_op.operators.iter()) {
+                        *step = frequency_to_step(base_steps * (op.mul as i64) / (op.div as i64), samplerate);
+                    }
+                },
+                InstrumentKind::Sample(ref sample) => {
+                    // 16-bit fixed point samples per frame.
+                    let rate = ((sample.rate as i64) * base_steps) / C4_FREQ;
+                    steps[0] = frequency_to_step(rate << 16, samplerate);
+                }
+            }
         }
+        // Otherwise, it's a pause. Dumbest way ever for marking pauses...
     }
 
-    pub fn get_adsr(&self, samplerate: i32, length: i32, pan: (i32, i32), volume: i32) -> ADSRState {
-        let mut adsr: ADSRState = Default::default();
-        adsr.cur_stage = 0;
-        let left = pan.0 * volume >> 8;
-        let right = pan.1 * volume >> 8;
-
-        // Attack
-        adsr.stages[0].frames_left = (self.attack as i32)*samplerate >> 12;
-        adsr.stages[0].amplitude_step.0 = (self.amplitude as i32) * left;
-        adsr.stages[0].amplitude_step.1 = (self.amplitude as i32) * right;
-        if adsr.stages[0].frames_left > 0 {
-            adsr.stages[0].amplitude_step.0 /= adsr.stages[0].frames_left;
-            adsr.stages[0].amplitude_step.1 /= adsr.stages[0].frames_left;
+    // Phases that notes start from when they don't continue from the previous
+    // note.
+    pub fn get_initial_phases(&self) -> [i32; 4] {
+        let mut phases = [0; 4];
+        if let InstrumentKind::TwoOp = self.kind {
+            phases[MODULATOR] = self.modulator_phase as i32;
         }
+        phases
+    }
 
-        // Decay
-        adsr.stages[1].frames_left = (self.decay as i32)*samplerate >> 12;
-        let decay_base_amplitude = (self.sustain as i32) - (self.amplitude as i32);
-        adsr.stages[1].amplitude_step = (decay_base_amplitude * left, decay_base_amplitude * right);
-        if adsr.stages[1].frames_left > 0 {
-            adsr.stages[1].amplitude_step.0 /= adsr.stages[1].frames_left;
-            adsr.stages[1].amplitude_step.1 /= adsr.stages[1].frames_left;
+    // If length is None, sustain holds until key_off.
+    pub fn get_adsr(&self, samplerate: i32, length: Option<i32>, pan: (i32, i32), volume: i32) -> ADSRState<'a> {
+        let left = (pan.0 * volume) >> 8;
+        let right = (pan.1 * volume) >> 8;
+        if let Some(envelope) = self.envelope {
+            return ADSRState {
+                points: Some(PointState {
+                    envelope,
+                    point: 0,
+                    gain: ((self.amplitude as i32) * left, (self.amplitude as i32) * right),
+                    samplerate,
+                    // Notes of a known length are released by the player.
+                    released: false
+                }),
+                ..Default::default()
+            };
         }
+        build_adsr(
+            samplerate,
+            length,
+            (self.attack, self.decay, self.release),
+            self.curves,
+            self.amplitude as i32,
+            self.sustain as i32,
+            (left, right)
+        )
+    }
 
-        // Release
-        let frames_so_far = adsr.stages[0].frames_left + adsr.stages[1].frames_left;
-        let frames_left = length - frames_so_far;
-        let intended_release = (self.release as i32)*samplerate >> 12;
+    pub fn get_modulator_adsr(&self, samplerate: i32, length: Option<i32>) -> ADSRState<'a> {
+        match self.modulator_envelope {
+            Some(ref env) => build_adsr(
+                samplerate,
+                length,
+                (env.attack, env.decay, env.release),
+                env.curves,
+                1<<16,
+                env.sustain as i32,
+                (1<<8, 0)
+            ),
+            // Jumps straight to full modulation and stays there.
+            None => build_adsr(samplerate, length, (0, 0, 0), [Curve::Linear; 3], 1<<16, 1<<16, (1<<8, 0))
+        }
+    }
 
-        adsr.stages[3].frames_left = if frames_left < intended_release {frames_left} else {intended_release};
-        let release_base_amplitude = -(self.sustain as i32);
-        adsr.stages[3].amplitude_step = (release_base_amplitude * left, release_base_amplitude * right);
-        if adsr.stages[3].frames_left > 0 {
-            adsr.stages[3].amplitude_step.0 /= adsr.stages[3].frames_left;
-            adsr.stages[3].amplitude_step.1 /= adsr.stages[3].frames_left;
+    // Starts releasing the envelopes of the voice from wherever they are.
+    pub fn key_off(&self, samplerate: i32, voice: &mut Voice) {
+        voice.adsr.release(((self.release as i32)*samplerate) >> 12, &mut voice.amplitude);
+        // Without a modulator envelope, modulation stays where it is.
+        if let Some(ref env) = self.modulator_envelope {
+            voice.modulator_adsr.release(((env.release as i32)*samplerate) >> 12, &mut voice.modulation);
         }
+    }
+}
 
-        // Sustain
-        adsr.stages[2].frames_left = length - adsr.stages[0].frames_left - adsr.stages[1].frames_left - adsr.stages[3].frames_left;
-        adsr.stages[2].amplitude_step = (0, 0);
+// Attack, decay and release are given in the same fixed point format as in
+// Instrument. Peak and sustain are 16-bit fixed point, and the gain of each
+// side is 8-bit fixed point. Without a length, sustain lasts until released.
+fn build_adsr<'a>(
+    samplerate: i32,
+    length: Option<i32>,
+    (attack, decay, release): (u16, u16, u16),
+    curves: [Curve; 3],
+    peak: i32,
+    sustain: i32,
+    (left, right): (i32, i32)
+) -> ADSRState<'a> {
+    let mut adsr: ADSRState = Default::default();
 
-        adsr
-    }
+    // Attack
+    adsr.stages[0] = ADSRStep::new(
+        ((attack as i32)*samplerate) >> 12,
+        (peak * left, peak * right),
+        curves[0]
+    );
+
+    // Decay
+    let decay_base_amplitude = sustain - peak;
+    adsr.stages[1] = ADSRStep::new(
+        ((decay as i32)*samplerate) >> 12,
+        (decay_base_amplitude * left, decay_base_amplitude * right),
+        curves[1]
+    );
+
+    // Release
+    let frames_so_far = adsr.stages[0].frames_left + adsr.stages[1].frames_left;
+    let intended_release = ((release as i32)*samplerate) >> 12;
+
+    // Notes shorter than attack and decay are cut right after them.
+    let release_frames = match length {
+        Some(length) if length - frames_so_far < intended_release => (length - frames_so_far).max(0),
+        _ => intended_release
+    };
+    let release_base_amplitude = -sustain;
+    adsr.stages[3] = ADSRStep::new(
+        release_frames,
+        (release_base_amplitude * left, release_base_amplitude * right),
+        curves[2]
+    );
+
+    // Sustain
+    let sustain_frames = match length {
+        Some(length) => (length - frames_so_far - adsr.stages[3].frames_left).max(0),
+        None => i32::MAX
+    };
+    adsr.stages[2] = ADSRStep::new(sustain_frames, (0, 0), Curve::Linear);
+
+    adsr
 }
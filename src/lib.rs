@@ -4,4 +4,5 @@ pub mod instrument;
 pub mod tune;
 pub mod channel;
 pub mod player;
+pub mod mixer;
 pub mod wave;
@@ -0,0 +1,110 @@
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Clipping {
+    Saturate, // Clamps to full scale.
+    SoftClip, // Bends smoothly towards full scale above half of it.
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Limiter {
+    pub threshold: u16, // 16-bit fixed point, fraction of full scale.
+    pub release: u16,   // 12-bit fixed point, in seconds. Time to recover from full reduction.
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Mixer {
+    pub gain: u16, // 8-bit fixed point. Values below 256 leave headroom for loud mixes.
+    pub clipping: Clipping,
+    pub limiter: Option<Limiter>,
+}
+
+impl Default for Mixer {
+    fn default() -> Mixer {
+        Mixer {
+            gain: 256,
+            clipping: Clipping::Saturate,
+            limiter: None
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct LimiterState {
+    pub gain: i32, // 16-bit fixed point
+}
+
+impl Default for LimiterState {
+    fn default() -> LimiterState {
+        LimiterState {
+            gain: 1<<16
+        }
+    }
+}
+
+// Maps magnitudes above the knee onto the rest of the range with
+// knee + range * over / (over + range), which starts at unit slope and never
+// reaches full scale.
+fn soft_clip(x: i64, full_scale: i64) -> i64 {
+    let knee = full_scale >> 1;
+    let magnitude = if x < 0 {-x} else {x};
+    if magnitude <= knee {
+        return x;
+    }
+    let over = magnitude - knee;
+    let range = full_scale - knee;
+    let y = knee + over * range / (over + range);
+    if x < 0 {-y} else {y}
+}
+
+impl Mixer {
+    // Applies gain, limiting and clipping to interleaved stereo samples in
+    // place. Output is clipped to [-full_scale, full_scale - 1].
+    pub fn process(
+        &self,
+        limiter_state: &mut LimiterState,
+        samplerate: i32,
+        full_scale: i32,
+        buffer: &mut [i32]
+    ) {
+        let full_scale = full_scale as i64;
+        let (threshold, release_step) = match self.limiter {
+            Some(ref limiter) => {
+                let threshold = (full_scale * limiter.threshold as i64) >> 16;
+                let release_frames = (limiter.release as i64 * samplerate as i64) >> 12;
+                let release_step = if release_frames > 0 {
+                    (1<<16) / release_frames + 1
+                } else {
+                    1<<16
+                };
+                (threshold, release_step)
+            },
+            None => (0, 0)
+        };
+        let mut limiter_gain = limiter_state.gain as i64;
+
+        for frame in buffer.chunks_mut(2) {
+            let mut left = (frame[0] as i64 * self.gain as i64) >> 8;
+            let mut right = (frame[frame.len()-1] as i64 * self.gain as i64) >> 8;
+
+            if self.limiter.is_some() {
+                // No lookahead, so the gain drops instantly on a peak and then
+                // recovers linearly.
+                let peak = left.abs().max(right.abs());
+                if (peak * limiter_gain) >> 16 > threshold {
+                    limiter_gain = (threshold << 16) / peak;
+                }
+                left = (left * limiter_gain) >> 16;
+                right = (right * limiter_gain) >> 16;
+                limiter_gain = (limiter_gain + release_step).min(1<<16);
+            }
+
+            for (x, y) in frame.iter_mut().zip([left, right].iter()) {
+                let y = match self.clipping {
+                    Clipping::Saturate => *y,
+                    Clipping::SoftClip => soft_clip(*y, full_scale)
+                };
+                *x = y.max(-full_scale).min(full_scale - 1) as i32;
+            }
+        }
+        limiter_state.gain = limiter_gain as i32;
+    }
+}
@@ -1,101 +1,486 @@
 use crate::tune::Tune;
-use crate::channel::Command;
-use crate::instrument::{Wavegen, WAVEGEN_TABLE, ADSRState};
+use crate::channel::{Command, note_names};
+use crate::instrument::{Wavegen, WAVEGEN_TABLE, Voice, LFOState, MacroState};
+use crate::mixer::{Mixer, LimiterState};
+
+// Length of a control block in frames. Pitch and other slowly changing
+// parameters are only updated between these, to keep them out of the wavegens.
+const CONTROL_FRAMES: i32 = 64;
+
+// Length of the mix buffer in frames. Channels are mixed into it at 32 bits
+// and then passed through the mixer into the output format.
+const MIX_FRAMES: usize = 128;
+
+// Maximum nesting of Call commands. Deeper calls overwrite the innermost
+// return address, so the outer calls still return.
+const CALL_DEPTH: usize = 4;
+
+// Maximum nesting of LoopStart commands, likewise overwriting the innermost
+// loop when exceeded.
+const LOOP_DEPTH: usize = 4;
+
+// Number of sound effects that can play at once.
+const SFX_SLOTS: usize = 4;
+
+// Wavegen output shifts for each output format.
+const I8_SHIFT: u32 = 23;
+const I16_SHIFT: u32 = 15;
+const F32_SHIFT: u32 = 7; // Keeps 24 bits, the precision of an f32.
 
 #[derive(Clone, Copy)]
-pub struct ChannelPlayer {
+pub struct ChannelPlayer<'t> {
     command_index: usize,
     instrument_index: usize,
     wavegen: Wavegen,
-    note_frames_left: i32,
-    carrier_step: i32,
-    carrier_phase: i32,
-    modulator_step: i32,
-    modulator_phase: i32,
+    note_ticks_left: i32,
+    release_frames_left: i32, // Until a Play note is released, i32::MAX if not timed.
+    tick_length: i32, // 8-bit fixed point frames, the current tempo
+    control_frames_left: i32,
+    note_steps: [i32; 4], // Timer steps of the note before any modulation.
+    pitch: i32, // 16-bit fixed point semitones, the pitch of note_steps.
+    target_pitch: i32, // Where the pitch is sliding to.
+    pitch_speed: i32, // Towards target_pitch per control block, 0 jumps.
+    slide: i32, // Pitch change per control block.
+    portamento: i32, // Pitch speed per tick for new notes, 0 if off.
+    glide: i32, // In ticks, 0 if off.
+    arpeggio: [u8; 4], // Semitone offsets cycled through, the first one is always 0.
+    arpeggio_length: usize, // 1 if arpeggio is off.
+    arpeggio_index: usize,
+    arpeggio_rate: i32, // Steps per tick
+    arpeggio_steps: usize, // Cycle length set by SetArpeggioSteps, 0 if automatic.
+    arpeggio_step: i32, // Within the current tick
+    arpeggio_frames_left: i32,
+    vibrato_amount: i32, // 16-bit fixed point, current pitch change from vibrato.
+    macros: [MacroState; 4], // Volume, arpeggio, pitch and duty
+    macros_released: bool,
+    macro_volume: i32, // 8-bit fixed point
+    macro_arpeggio: i32, // In semitones
+    macro_pitch: i32, // 16-bit fixed point semitones
+    macro_duty: Option<u16>,
+    voice: Voice<'t>,
+    vibrato: LFOState,
+    tremolo: LFOState,
+    pwm: LFOState,
     pan: (i32, i32), // 8-bit fixed point
-    amplitude: (i32, i32), // 24-bit fixed point (to avoid some rounding stupidity)
-    adsr: ADSRState,
     repeat_counter: i32,
+    return_stack: [usize; CALL_DEPTH],
+    call_depth: usize,
+    loop_stack: [(usize, i32); LOOP_DEPTH], // Start index and passes played
+    loop_depth: usize,
     global_volume: i32, // 8-bit fixed point
+    ended: bool, // No more commands to run, though the release may still play.
+    held: bool, // The note was started with Hold and not released yet.
+    playing: bool, // The ticks of a Play note are still running.
+    play_pending: bool, // A Play note waits for the other channels, see start_played_note.
+    priority: u8,
 }
 
-impl Default for ChannelPlayer {
-    fn default() -> ChannelPlayer {
+impl<'t> Default for ChannelPlayer<'t> {
+    fn default() -> ChannelPlayer<'t> {
         ChannelPlayer {
             command_index: 0,
             instrument_index: 0,
             wavegen: WAVEGEN_TABLE[0][0],
-            note_frames_left: 0,
-            carrier_step: 0,
-            carrier_phase: 0,
-            modulator_step: 0,
-            modulator_phase: 0,
+            note_ticks_left: 0,
+            release_frames_left: i32::MAX,
+            tick_length: 1<<8,
+            control_frames_left: 0,
+            note_steps: [0; 4],
+            pitch: (note_names::PAUSE as i32) << 16,
+            target_pitch: (note_names::PAUSE as i32) << 16,
+            pitch_speed: 0,
+            slide: 0,
+            portamento: 0,
+            glide: 0,
+            arpeggio: [0; 4],
+            arpeggio_length: 1,
+            arpeggio_index: 0,
+            arpeggio_rate: 1,
+            arpeggio_steps: 0,
+            arpeggio_step: 0,
+            arpeggio_frames_left: 0,
+            vibrato_amount: 0,
+            macros: Default::default(),
+            macros_released: false,
+            macro_volume: 256,
+            macro_arpeggio: 0,
+            macro_pitch: 0,
+            macro_duty: None,
+            voice: Default::default(),
+            vibrato: Default::default(),
+            tremolo: Default::default(),
+            pwm: Default::default(),
             pan: (1<<8, 1<<8),
-            amplitude: (0, 0),
-            adsr: Default::default(),
             repeat_counter: 0,
-            global_volume: 256
+            return_stack: [0; CALL_DEPTH],
+            call_depth: 0,
+            loop_stack: [(0, 0); LOOP_DEPTH],
+            loop_depth: 0,
+            global_volume: 256,
+            ended: false,
+            held: false,
+            playing: false,
+            play_pending: false,
+            priority: 0
         }
     }
 }
 
-impl ChannelPlayer {
+impl<'t> ChannelPlayer<'t> {
+    // Generates frames within a single tick.
     fn generate(
         &mut self,
-        tune: &Tune,
-        command_stream: &[Command],
-        out: &mut [i8]
+        tune: &Tune<'t>,
+        out: &mut [i32],
+        shift: u32
     ) {
         let mut frames_left: i32 = (out.len()>>1) as i32;
         let mut start_frame: usize = 0;
 
         while frames_left > 0 {
-            let step_frames = if frames_left < self.note_frames_left {
+            let mut step_frames = if frames_left < self.control_frames_left {
                 frames_left
             } else {
-                self.note_frames_left
+                self.control_frames_left
             };
+            let arpeggio = self.arpeggio_length > 1;
+            if arpeggio && self.arpeggio_frames_left < step_frames {
+                step_frames = self.arpeggio_frames_left;
+            }
+            let timed_release = self.release_frames_left != i32::MAX;
+            if timed_release && self.release_frames_left < step_frames {
+                step_frames = self.release_frames_left;
+            }
+
+            // Point envelopes can end on any level, so an ended channel fades
+            // out from there.
+            if self.ended && self.voice.adsr.points.is_some() && self.voice.adsr.is_finished() {
+                let release = tune.instruments[self.instrument_index].release as i32;
+                let frames = ((release * tune.samplerate) >> 12).max(tune.samplerate >> 8);
+                self.voice.adsr.fade_out(frames, &mut self.voice.amplitude);
+            }
 
             let end_frame = start_frame+(step_frames as usize);
-            if self.carrier_step != 0 {
+            if !self.is_finished() && self.voice.steps != [0; 4] {
                 (self.wavegen)(
                     &tune.instruments[self.instrument_index],
-                    &mut self.adsr,
-                    &mut self.amplitude,
-                    self.carrier_step,
-                    &mut self.carrier_phase,
-                    self.modulator_step,
-                    &mut self.modulator_phase,
-                    &mut out[start_frame*2..end_frame*2]
+                    tune.wavetables,
+                    &mut self.voice,
+                    &mut out[start_frame*2..end_frame*2],
+                    shift
                 );
             }
 
             start_frame += step_frames as usize;
             frames_left -= step_frames;
-            self.note_frames_left -= step_frames;
+            self.control_frames_left -= step_frames;
+            if timed_release {
+                self.release_frames_left -= step_frames;
+                if self.release_frames_left <= 0 {
+                    self.release_note(tune);
+                }
+            }
+            if arpeggio {
+                self.arpeggio_frames_left -= step_frames;
+                if self.arpeggio_frames_left <= 0 {
+                    if self.arpeggio_step + 1 < self.arpeggio_rate {
+                        self.arpeggio_step += 1;
+                        self.advance_arpeggio(tune);
+                    } else {
+                        // The last step lasts until the next tick.
+                        self.arpeggio_frames_left = i32::MAX;
+                    }
+                }
+            }
+            if self.control_frames_left <= 0 {
+                self.update_control(tune);
+            }
+        }
+    }
 
-            if self.note_frames_left <= 0 {
-                self.execute(tune, command_stream);
+    // Advances to the next tick of the given length: steps the per-tick
+    // effects and runs the commands once the current note is over. SetTempo
+    // leaves its tick length in `new_tick_length`, and Play notes are started
+    // by start_played_note.
+    fn tick(
+        &mut self,
+        tune: &Tune<'t>,
+        command_stream: &[Command],
+        tick_length: i32,
+        new_tick_length: &mut Option<i32>
+    ) {
+        self.tick_length = tick_length;
+        if self.arpeggio_length > 1 {
+            self.arpeggio_step = 0;
+            self.advance_arpeggio(tune);
+        }
+        self.step_macros(tune);
+        self.note_ticks_left -= 1;
+        if self.note_ticks_left <= 0 && !self.ended {
+            self.execute(tune, command_stream, new_tick_length);
+        }
+    }
+
+    // True once the channel has ended and its last note has been released.
+    pub fn is_finished(&self) -> bool {
+        self.ended && (self.voice.steps == [0; 4] || self.voice.adsr.is_finished())
+    }
+
+    // Ends a Play note once its length is over. ADSR envelopes have their
+    // release built in, but point envelopes and macros are released here.
+    fn release_note(&mut self, tune: &Tune<'t>) {
+        self.release_frames_left = i32::MAX;
+        self.macros_released = true;
+        let instrument = &tune.instruments[self.instrument_index];
+        if instrument.envelope.is_some() {
+            instrument.key_off(tune.samplerate, &mut self.voice);
+        }
+    }
+
+    // Starts a note played this tick. This waits until every channel has run
+    // its commands, so that the length follows a SetTempo from any of them:
+    // the first tick is at the current tempo and the rest at the next one.
+    fn start_played_note(&mut self, tune: &Tune<'t>, next_tick_length: i32) {
+        if self.play_pending {
+            self.play_pending = false;
+            let ticks = (self.note_ticks_left - 1).max(0) as i64;
+            let length = (self.tick_length as i64 + next_tick_length as i64 * ticks) >> 8;
+            self.start_note(tune, Some(length as i32));
+        }
+    }
+
+    // Follows slides through a tick without generating it.
+    fn skip_tick(&mut self, tune: &Tune<'t>) {
+        if self.slide != 0 || self.pitch != self.target_pitch {
+            for _ in 0..(self.tick_length >> 8) / CONTROL_FRAMES {
+                self.update_pitch(tune);
+            }
+        }
+    }
+
+    // Restarts the note that was playing when seeking ended, for whatever is
+    // left of it. Released notes are cut.
+    fn resume(&mut self, tune: &Tune<'t>) {
+        if self.held {
+            self.start_note(tune, None);
+        } else if self.playing && self.note_ticks_left > 1 {
+            self.start_note(tune, Some(self.ticks_to_frames((self.note_ticks_left - 1) as u8)));
+        } else {
+            self.voice.adsr = Default::default();
+            self.voice.adsr.init_stage_amplitude(&mut self.voice.amplitude);
+        }
+    }
+
+    // Converts a pitch change per tick into one per control block.
+    fn per_control_block(&self, amount: i32) -> i32 {
+        ((amount as i64) * (CONTROL_FRAMES as i64) * 256 / (self.tick_length as i64)) as i32
+    }
+
+    // Length of the given number of ticks in frames, at the current tempo.
+    fn ticks_to_frames(&self, ticks: u8) -> i32 {
+        ((self.tick_length as i64 * ticks as i64) >> 8) as i32
+    }
+
+    fn set_pitch(&mut self, tune: &Tune<'t>, pitch: i32) {
+        self.pitch = pitch;
+        let max_pitch = (note_names::B8 as i32) << 16;
+        let mut arpeggio_pitch = pitch;
+        if pitch <= max_pitch {
+            let semitones = self.arpeggio[self.arpeggio_index] as i32 + self.macro_arpeggio;
+            arpeggio_pitch = (pitch + (semitones << 16) + self.macro_pitch).max(0).min(max_pitch);
+        }
+        tune.instruments[self.instrument_index].get_fine_timer_steps(
+            tune.samplerate,
+            arpeggio_pitch,
+            &mut self.note_steps
+        );
+    }
+
+    fn advance_arpeggio(&mut self, tune: &Tune<'t>) {
+        self.arpeggio_index = (self.arpeggio_index + 1) % self.arpeggio_length;
+        self.restart_arpeggio_step(tune);
+    }
+
+    // Applies the current arpeggio offset and starts timing the step.
+    fn restart_arpeggio_step(&mut self, tune: &Tune<'t>) {
+        self.arpeggio_frames_left = ((self.tick_length >> 8) / self.arpeggio_rate).max(1);
+        self.set_pitch(tune, self.pitch);
+        self.apply_vibrato();
+    }
+
+    // Steps the instrument macros, once per tick.
+    fn step_macros(&mut self, tune: &Tune<'t>) {
+        let macros = match tune.instruments[self.instrument_index].macros {
+            Some(ref macros) => macros,
+            None => return
+        };
+        let released = self.macros_released;
+        if let Some(ref volume) = macros.volume {
+            self.macro_volume = self.macros[0].advance(volume, released).clamp(0, 256);
+        }
+        if let Some(ref arpeggio) = macros.arpeggio {
+            self.macro_arpeggio = self.macros[1].advance(arpeggio, released);
+        }
+        if let Some(ref pitch) = macros.pitch {
+            self.macro_pitch += self.macros[2].advance(pitch, released) << 12;
+        }
+        if let Some(ref duty) = macros.duty {
+            let duty = self.macros[3].advance(duty, released) << 8;
+            self.macro_duty = Some(duty.clamp(0, u16::MAX as i32) as u16);
+        }
+        self.set_pitch(tune, self.pitch);
+        self.apply_vibrato();
+    }
+
+    fn apply_vibrato(&mut self) {
+        self.voice.steps = self.note_steps;
+        for step in self.voice.steps.iter_mut() {
+            *step += ((*step as i64 * self.vibrato_amount as i64) >> 16) as i32;
+        }
+    }
+
+    // Moves the pitch along slides and towards the target pitch.
+    fn update_pitch(&mut self, tune: &Tune<'t>) {
+        let max_pitch = (note_names::B8 as i32) << 16;
+        if self.pitch > max_pitch {
+            return;
+        }
+        let clamp = |pitch: i32| if pitch < 0 {0} else if pitch > max_pitch {max_pitch} else {pitch};
+        self.target_pitch = clamp(self.target_pitch + self.slide);
+        let mut pitch = clamp(self.pitch + self.slide);
+        if self.pitch_speed == 0 {
+            pitch = self.target_pitch;
+        } else if pitch < self.target_pitch {
+            pitch = (pitch + self.pitch_speed).min(self.target_pitch);
+        } else {
+            pitch = (pitch - self.pitch_speed).max(self.target_pitch);
+        }
+        self.set_pitch(tune, pitch);
+    }
+
+    fn update_control(&mut self, tune: &Tune<'t>) {
+        if self.slide != 0 || self.pitch != self.target_pitch {
+            self.update_pitch(tune);
+        }
+        let instrument = &tune.instruments[self.instrument_index];
+        self.vibrato_amount = match instrument.vibrato {
+            Some(ref vibrato) => self.vibrato.advance(vibrato, tune.samplerate, CONTROL_FRAMES, tune.wavetables),
+            None => 0
+        };
+        self.apply_vibrato();
+        if instrument.tremolo.is_some() || self.macro_volume != 256 {
+            let mut gain = 1<<15;
+            if let Some(ref tremolo) = instrument.tremolo {
+                let depth = self.tremolo.depth(tremolo, tune.samplerate);
+                let amount = self.tremolo.advance(tremolo, tune.samplerate, CONTROL_FRAMES, tune.wavetables);
+                gain -= (depth - amount) >> 2;
+            }
+            gain = (gain * self.macro_volume) >> 8;
+            // Ramps to the new gain over the block to avoid zipper noise.
+            self.voice.tremolo_step = (gain - self.voice.tremolo) / CONTROL_FRAMES;
+        } else {
+            self.voice.tremolo = 1<<15;
+            self.voice.tremolo_step = 0;
+        }
+        let mut duty = self.macro_duty.unwrap_or(instrument.duty) as i32;
+        if let Some(ref pwm) = instrument.pwm {
+            duty += self.pwm.advance(pwm, tune.samplerate, CONTROL_FRAMES, tune.wavetables);
+        }
+        self.voice.duty = if duty < 0 {
+            0
+        } else if duty > u16::MAX as i32 {
+            u16::MAX
+        } else {
+            duty as u16
+        };
+        self.control_frames_left = CONTROL_FRAMES;
+    }
+
+    // Starts the envelopes and LFOs of a new note. Without a length, the note
+    // sustains until KeyOff.
+    fn start_note(&mut self, tune: &Tune<'t>, length: Option<i32>) {
+        // Only legato notes glide, but portamento is explicit.
+        if self.portamento == 0 && self.pitch != self.target_pitch {
+            self.set_pitch(tune, self.target_pitch);
+        }
+        if self.arpeggio_length > 1 {
+            self.arpeggio_index = 0;
+            self.arpeggio_step = 0;
+            self.restart_arpeggio_step(tune);
+        }
+        self.release_frames_left = length.unwrap_or(i32::MAX);
+        self.macros = Default::default();
+        self.macros_released = false;
+        self.macro_volume = 256;
+        self.macro_arpeggio = 0;
+        self.macro_pitch = 0;
+        self.macro_duty = None;
+        self.step_macros(tune);
+        self.control_frames_left = 0;
+        self.vibrato = Default::default();
+        self.tremolo = Default::default();
+        self.pwm = Default::default();
+        self.voice.amplitude = (0, 0);
+        let instrument = &tune.instruments[self.instrument_index];
+        self.voice.adsr = instrument.get_adsr(tune.samplerate, length, self.pan, self.global_volume);
+        self.voice.adsr.init_stage_amplitude(&mut self.voice.amplitude);
+        self.voice.modulation = (0, 0);
+        // Point envelopes ring past the length, so modulation is released
+        // along with them by key_off.
+        let modulator_length = if instrument.envelope.is_some() {None} else {length};
+        self.voice.modulator_adsr = instrument.get_modulator_adsr(tune.samplerate, modulator_length);
+        self.voice.modulator_adsr.init_stage_amplitude(&mut self.voice.modulation);
+
+        self.voice.sample_position = 0;
+
+        // We can only reset phase if the initial amplitude is zero.
+        if self.voice.amplitude == (0, 0) {
+            self.voice.phases = instrument.get_initial_phases();
+            self.voice.feedback = (0, 0);
+        } else {
+            // Otherwise, we have to continue where we left off to
+            // avoid clicks in the sound. This messes up
+            // carrier-modulator synchronization.
+            for phase in self.voice.phases.iter_mut() {
+                *phase &= 0xFFFF;
             }
         }
     }
 
     fn execute(
         &mut self,
-        tune: &Tune,
-        command_stream: &[Command]
+        tune: &Tune<'t>,
+        command_stream: &[Command],
+        new_tick_length: &mut Option<i32>
     ) {
         loop {
             let cur_command_index = self.command_index;
             self.command_index += 1;
-            match command_stream[cur_command_index] {
+            let command = if cur_command_index < command_stream.len() {
+                &command_stream[cur_command_index]
+            } else {
+                &Command::End
+            };
+            match *command {
                 Command::Note(pitch) => {
-                    tune.instruments[self.instrument_index].get_timer_steps(
-                        tune.samplerate,
-                        pitch as i32,
-                        &mut self.carrier_step,
-                        &mut self.modulator_step
-                    );
+                    let max_pitch = (note_names::B8 as i32) << 16;
+                    let distance = ((pitch as i32) << 16) - self.pitch;
+                    self.target_pitch = (pitch as i32) << 16;
+                    self.slide = 0;
+                    let sliding = self.pitch <= max_pitch && self.target_pitch <= max_pitch;
+                    if sliding && self.portamento != 0 {
+                        self.pitch_speed = self.per_control_block(self.portamento).max(1);
+                    } else if sliding && self.glide != 0 {
+                        self.pitch_speed = (
+                            (distance.abs() as i64) * (CONTROL_FRAMES as i64) * 256 /
+                            ((self.glide as i64) * (self.tick_length as i64))
+                        ).max(1) as i32;
+                    } else {
+                        // Applied right away, legato notes don't restart the
+                        // control block.
+                        self.set_pitch(tune, self.target_pitch);
+                        self.apply_vibrato();
+                    }
                 },
                 Command::SetInstrument(index) => {
                     self.instrument_index = index as usize;
@@ -105,26 +490,98 @@ impl ChannelPlayer {
                     self.global_volume = volume as i32;
                 },
                 Command::Play(ticks) => {
-                    self.note_frames_left = tune.tick_length * ticks as i32;
-                    self.amplitude = (0, 0);
-                    let instrument = &tune.instruments[self.instrument_index]; 
-                    self.adsr = instrument.get_adsr(tune.samplerate, self.note_frames_left, self.pan, self.global_volume);
-                    self.adsr.init_stage_amplitude(&mut self.amplitude);
-
-                    // We can only reset phase if the initial amplitude is zero.
-                    if self.amplitude == (0, 0) {
-                        self.carrier_phase = 0;
-                        self.modulator_phase = instrument.modulator_phase as i32;
-                    } else {
-                        // Otherwise, we have to continue where we left off to
-                        // avoid clicks in the sound. This messes up
-                        // carrier-modulator synchronization.
-                        self.carrier_phase = self.carrier_phase&0xFFFF;
-                        self.modulator_phase = self.modulator_phase&0xFFFF;
+                    self.held = false;
+                    self.playing = true;
+                    self.play_pending = true;
+                    self.note_ticks_left = ticks as i32;
+                    break;
+                },
+                Command::Hold(ticks) => {
+                    self.held = true;
+                    self.playing = false;
+                    self.note_ticks_left = ticks as i32;
+                    self.start_note(tune, None);
+                    break;
+                },
+                Command::SlideUp(speed) => {
+                    self.slide = self.per_control_block((speed as i32) << 12);
+                },
+                Command::SlideDown(speed) => {
+                    self.slide = -self.per_control_block((speed as i32) << 12);
+                },
+                Command::Portamento(speed) => {
+                    self.portamento = (speed as i32) << 12;
+                    if speed == 0 {
+                        self.target_pitch = self.pitch;
+                    }
+                },
+                Command::SetGlide(ticks) => self.glide = ticks as i32,
+                Command::Arpeggio(a, b, c) => {
+                    self.arpeggio = [0, a, b, c];
+                    // Trailing zero offsets are left out of the cycle, unless
+                    // its length is fixed.
+                    self.arpeggio_length = 1;
+                    for (i, &offset) in self.arpeggio.iter().enumerate() {
+                        if offset != 0 {
+                            self.arpeggio_length = i + 1;
+                        }
+                    }
+                    if self.arpeggio_steps > 0 && self.arpeggio_length > 1 {
+                        self.arpeggio_length = self.arpeggio_steps;
                     }
+                    self.arpeggio_index = 0;
+                    self.arpeggio_step = 0;
+                    self.restart_arpeggio_step(tune);
+                },
+                Command::SetArpeggioRate(rate) => self.arpeggio_rate = (rate as i32).max(1),
+                Command::SetArpeggioSteps(steps) => self.arpeggio_steps = (steps as usize).min(4),
+                Command::SetTempo(ticks_per_minute) => {
+                    let frames = ((tune.samplerate as i64 * 60) << 8) / (ticks_per_minute as i64).max(1);
+                    *new_tick_length = Some(frames.max(1<<8) as i32);
+                },
+                Command::Wait(ticks) => {
+                    self.playing = false;
+                    self.note_ticks_left = ticks as i32;
                     break;
                 },
+                Command::KeyOff => {
+                    tune.instruments[self.instrument_index].key_off(tune.samplerate, &mut self.voice);
+                    self.macros_released = true;
+                    self.release_frames_left = i32::MAX;
+                    self.held = false;
+                    self.playing = false;
+                },
                 Command::Jump(index) => self.command_index = index as usize,
+                Command::Call(index) => {
+                    if self.call_depth < CALL_DEPTH {
+                        self.call_depth += 1;
+                    }
+                    self.return_stack[self.call_depth - 1] = self.command_index;
+                    self.command_index = index as usize;
+                },
+                Command::LoopStart => {
+                    if self.loop_depth < LOOP_DEPTH {
+                        self.loop_depth += 1;
+                    }
+                    self.loop_stack[self.loop_depth - 1] = (self.command_index, 0);
+                },
+                Command::LoopEnd(count) => {
+                    if self.loop_depth > 0 {
+                        let (start, ref mut passes) = self.loop_stack[self.loop_depth - 1];
+                        *passes += 1;
+                        if *passes < count as i32 {
+                            self.command_index = start;
+                        } else {
+                            self.loop_depth -= 1;
+                        }
+                    }
+                },
+                Command::Return => {
+                    if self.call_depth > 0 {
+                        self.call_depth -= 1;
+                        self.command_index = self.return_stack[self.call_depth];
+                    }
+                },
                 Command::Repeat(count) => {
                     if self.repeat_counter == 0 {
                         self.repeat_counter = count as i32;
@@ -135,6 +592,16 @@ impl ChannelPlayer {
                         }
                     }
                 },
+                Command::SetPriority(priority) => self.priority = priority,
+                Command::End => {
+                    tune.instruments[self.instrument_index].key_off(tune.samplerate, &mut self.voice);
+                    self.macros_released = true;
+                    self.release_frames_left = i32::MAX;
+                    self.held = false;
+                    self.playing = false;
+                    self.ended = true;
+                    break;
+                },
                 Command::Pan(pan) => {
                     if pan <= 0 {
                         self.pan.0 = 1<<8;
@@ -149,28 +616,248 @@ impl ChannelPlayer {
     }
 }
 
-pub struct Player<'a> {
-    pub tune: &'static Tune,
-    pub channels: &'a mut [ChannelPlayer]
+// A sound effect playing in place of a music channel.
+#[derive(Clone, Copy)]
+struct Sfx<'t> {
+    channel: usize,
+    priority: u8,
+    commands: &'t [Command],
+    player: ChannelPlayer<'t>
+}
+
+pub struct Player<'a, 't> {
+    pub tune: &'t Tune<'t>,
+    pub channels: &'a mut [ChannelPlayer<'t>],
+    pub mixer: Mixer,
+    limiter: LimiterState,
+    song: usize,
+    tick_length: i32, // 8-bit fixed point frames
+    // Set by SetTempo, applied before the next tick so that every channel
+    // sees the same tempo.
+    new_tick_length: Option<i32>,
+    tick_frames_left: i32, // 8-bit fixed point
+    sfx: [Option<Sfx<'t>>; SFX_SLOTS]
 }
 
-impl<'a> Player<'a> {
+impl<'a, 't> Player<'a, 't> {
     pub fn new(
-        tune: &'static Tune,
-        channels: &'a mut [ChannelPlayer]
+        tune: &'t Tune<'t>,
+        channels: &'a mut [ChannelPlayer<'t>]
     ) -> Self {
         Self {
             tune,
-            channels
+            channels,
+            mixer: Default::default(),
+            limiter: Default::default(),
+            song: 0,
+            tick_length: tune.songs[0].tick_length,
+            new_tick_length: None,
+            tick_frames_left: 0,
+            sfx: [None; SFX_SLOTS]
         }
     }
 
-    pub fn generate(&mut self, out: &mut[i8]) {
-        for x in out.iter_mut() {
-            *x = 0;
+    // Mixes all channels into `out` in chunks of MIX_FRAMES, with wavegen
+    // output shifted down by `shift`. The mixer clips to the output range
+    // before `convert` is applied.
+    fn mix<T, F: Fn(i32) -> T>(&mut self, out: &mut [T], shift: u32, convert: F) {
+        let full_scale = 1<<(30-shift);
+        let tune = self.tune;
+        let song = &tune.songs[self.song];
+        let mut mix_buffer = [0i32; MIX_FRAMES*2];
+        for chunk in out.chunks_mut(MIX_FRAMES*2) {
+            let mix = &mut mix_buffer[..chunk.len()];
+            for x in mix.iter_mut() {
+                *x = 0;
+            }
+            // Ticks fall between frames, so the fractional part carries over
+            // to keep long songs in time.
+            let frames = mix.len() >> 1;
+            let mut start_frame = 0;
+            while start_frame < frames {
+                if self.tick_frames_left <= 0 {
+                    if let Some(tick_length) = self.new_tick_length.take() {
+                        self.tick_length = tick_length;
+                    }
+                    self.tick_sfx();
+                    for i in 0..song.channels.len() {
+                        self.channels[i].tick(tune, song.channels[i], self.tick_length, &mut self.new_tick_length);
+                        if self.sfx_slot(i).is_some() {
+                            self.channels[i].skip_tick(tune);
+                        }
+                    }
+                    self.start_played_notes();
+                    self.tick_frames_left += self.tick_length;
+                }
+                let step_frames = (((self.tick_frames_left + 255) >> 8) as usize).min(frames - start_frame);
+                let end_frame = start_frame + step_frames;
+                let out = &mut mix[start_frame*2..end_frame*2];
+                for i in 0..song.channels.len() {
+                    match self.sfx_slot(i) {
+                        Some(slot) => {
+                            if let Some(ref mut sfx) = self.sfx[slot] {
+                                sfx.player.generate(tune, out, shift);
+                            }
+                        },
+                        None => self.channels[i].generate(tune, out, shift)
+                    }
+                }
+                self.tick_frames_left -= (step_frames as i32) << 8;
+                start_frame = end_frame;
+            }
+            self.mixer.process(&mut self.limiter, tune.samplerate, full_scale, mix);
+            for (x, y) in chunk.iter_mut().zip(mix.iter()) {
+                *x = convert(*y);
+            }
         }
-        for i in 0..self.tune.channels.len() {
-            self.channels[i].generate(self.tune, self.tune.channels[i], out);
+    }
+
+    // Restarts the tune from the given tick. The sequencers are run up to it
+    // without generating anything, and notes still playing there restart.
+    pub fn seek(&mut self, tick: u32) {
+        let tune = self.tune;
+        let song = &tune.songs[self.song];
+        for channel in self.channels.iter_mut() {
+            *channel = Default::default();
+        }
+        self.limiter = Default::default();
+        self.tick_length = song.tick_length;
+        self.new_tick_length = None;
+        for _ in 0..tick {
+            if let Some(tick_length) = self.new_tick_length.take() {
+                self.tick_length = tick_length;
+            }
+            for i in 0..song.channels.len() {
+                self.channels[i].tick(tune, song.channels[i], self.tick_length, &mut self.new_tick_length);
+                self.channels[i].skip_tick(tune);
+            }
+            self.start_played_notes();
+        }
+        for i in 0..song.channels.len() {
+            self.channels[i].resume(tune);
+        }
+        self.tick_frames_left = 0;
+    }
+
+    // Switches to another song of the tune, playing it from the start. There
+    // have to be enough channels for it. Sound effects keep playing if their
+    // channel is still there.
+    pub fn set_song(&mut self, song: usize) {
+        self.song = song;
+        let channels = self.tune.songs[song].channels.len();
+        for slot in self.sfx.iter_mut() {
+            if slot.is_some_and(|sfx| sfx.channel >= channels) {
+                *slot = None;
+            }
+        }
+        self.seek(0);
+    }
+
+    // Plays a sound effect in place of a music channel, which keeps running
+    // silently and comes back in sync once the effect has finished. The
+    // effect starts on the next tick and follows the music tempo, ignoring
+    // its own SetTempo commands. Without a channel, the one with the lowest
+    // priority is taken. Channels playing music or effects of a higher
+    // priority are left alone. Returns the channel, if any.
+    pub fn play_sfx(
+        &mut self,
+        commands: &'t [Command],
+        priority: u8,
+        channel: Option<usize>
+    ) -> Option<usize> {
+        let free_slot = self.sfx.iter().position(|slot| slot.is_none());
+        // Among equal priorities, channels that are done with their music
+        // go first.
+        let mut target: Option<(usize, (u8, bool))> = None;
+        for i in 0..self.tune.songs[self.song].channels.len() {
+            if channel.is_some_and(|channel| channel != i) {
+                continue;
+            }
+            let current = match self.sfx_slot(i) {
+                Some(slot) => (self.sfx[slot].map_or(0, |sfx| sfx.priority), true),
+                None if free_slot.is_some() => (self.channels[i].priority, !self.channels[i].is_finished()),
+                None => continue
+            };
+            if current.0 <= priority && target.is_none_or(|(_, lowest)| current < lowest) {
+                target = Some((i, current));
+            }
+        }
+        let (channel, _) = target?;
+        let slot = match self.sfx_slot(channel) {
+            Some(slot) => slot,
+            None => free_slot?
+        };
+        self.sfx[slot] = Some(Sfx {
+            channel,
+            priority,
+            commands,
+            player: Default::default()
+        });
+        Some(channel)
+    }
+
+    // Starts the notes played on this tick, once the tempo of the next one is
+    // known.
+    fn start_played_notes(&mut self) {
+        let tune = self.tune;
+        let next_tick_length = self.new_tick_length.unwrap_or(self.tick_length);
+        let channels = tune.songs[self.song].channels.len();
+        for channel in self.channels[..channels].iter_mut() {
+            channel.start_played_note(tune, next_tick_length);
+        }
+        for sfx in self.sfx.iter_mut().flatten() {
+            sfx.player.start_played_note(tune, next_tick_length);
         }
     }
+
+    fn sfx_slot(&self, channel: usize) -> Option<usize> {
+        self.sfx.iter().position(|slot| slot.is_some_and(|sfx| sfx.channel == channel))
+    }
+
+    // Runs the sound effects for a tick, handing finished ones back to the
+    // music.
+    fn tick_sfx(&mut self) {
+        let tune = self.tune;
+        let tick_length = self.tick_length;
+        for slot in self.sfx.iter_mut() {
+            let finished = match *slot {
+                Some(ref mut sfx) if !sfx.player.is_finished() => {
+                    // Tempo changes are dropped so that effects can't change
+                    // the music.
+                    sfx.player.tick(tune, sfx.commands, tick_length, &mut None);
+                    false
+                },
+                Some(_) => true,
+                None => false
+            };
+            if finished {
+                if let Some(sfx) = slot.take() {
+                    self.channels[sfx.channel].resume(tune);
+                }
+            }
+        }
+    }
+
+    pub fn song(&self) -> usize {
+        self.song
+    }
+
+    // True once every channel has ended and finished its release.
+    pub fn is_finished(&self) -> bool {
+        let channels = self.tune.songs[self.song].channels.len();
+        self.channels[..channels].iter().all(|channel| channel.is_finished())
+    }
+
+    pub fn generate(&mut self, out: &mut [i8]) {
+        self.mix(out, I8_SHIFT, |x| x as i8);
+    }
+
+    pub fn generate_i16(&mut self, out: &mut [i16]) {
+        self.mix(out, I16_SHIFT, |x| x as i16);
+    }
+
+    // Samples are in the range [-1, 1).
+    pub fn generate_f32(&mut self, out: &mut [f32]) {
+        self.mix(out, F32_SHIFT, |x| x as f32 / (1<<(30-F32_SHIFT)) as f32);
+    }
 }
@@ -1,10 +1,18 @@
 use crate::instrument::Instrument;
 use crate::channel::Command;
+use crate::wave::Wavetable;
 
-pub struct Tune {
+pub struct Song<'a> {
+    pub tick_length: i32, // 8-bit fixed point, in frames. The initial tempo.
+    pub channels: &'a[&'a[Command]]
+}
+
+// Songs share the instruments and wavetables. Const tunes are simply
+// Tune<'static>, but the data can also be built or loaded at runtime.
+pub struct Tune<'a> {
     pub samplerate: i32,
-    pub tick_length: i32,
-    pub instruments: &'static[Instrument],
-    pub channels: &'static[&'static[Command]]
+    pub instruments: &'a[Instrument<'a>],
+    pub wavetables: &'a[Wavetable<'a>], // For Waveform::Table
+    pub songs: &'a[Song<'a>]
 }
 
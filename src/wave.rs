@@ -1,6 +1,6 @@
 pub fn i16cos(x: i16) -> i16 {
     let mask = (x>>15) as i32;
-    let t: i32 = ((x as i32)^mask) as i32;
+    let t: i32 = (x as i32)^mask;
 
     let mut u = 1127;
     u = (u * t - 55399812) >> 15;
@@ -12,16 +12,77 @@ pub fn i16square(x: i16) -> i16 {
     if x < 0 {-0x7FFF} else {0x7FFF}
 }
 
+// Duty is the fraction of the cycle that is high, in 16-bit fixed point. A
+// duty of 0 is silent rather than a constant low level, so that modulation
+// reaching it doesn't thump.
+pub fn i16pulse(x: i16, duty: u16) -> i16 {
+    if duty == 0 {
+        0
+    } else if ((x as u16) ^ 0x8000) as u32 + duty as u32 >= 0x10000 {
+        0x7FFF
+    } else {
+        -0x7FFF
+    }
+}
+
 pub fn i16triangle(x: i16) -> i16 {
-    (0x7FFFi16.wrapping_sub((if x < 0 {(-1i16).wrapping_sub(x)} else {x}) << 1)) as i16
+    0x7FFFi16.wrapping_sub((if x < 0 {(-1i16).wrapping_sub(x)} else {x}) << 1)
 }
 
 pub fn i16saw(x: i16) -> i16 {
     x
 }
 
+// PolyBLEP residual for a jump at t = 0, where t is the phase as a 16-bit
+// fraction of the cycle and dt is the phase step. Result is 15-bit fixed point.
+fn polyblep(t: i32, dt: i32) -> i32 {
+    let dt = if dt < 0 {-dt} else {dt};
+    if dt == 0 || dt >= 0x8000 {
+        0
+    } else if t < dt {
+        let x = (t << 15) / dt;
+        x + x - ((x * x) >> 15) - 0x8000
+    } else if t > 0x10000 - dt {
+        let x = ((t - 0x10000) << 15) / dt;
+        ((x * x) >> 15) + x + x + 0x8000
+    } else {
+        0
+    }
+}
+
+// Band-limited versions of i16square and i16saw, dt is the phase step of the
+// current frame.
+pub fn i16blsquare(x: i16, dt: i32) -> i16 {
+    let t = ((x as u16) ^ 0x8000) as i32;
+    let value = i16square(x) as i32 - polyblep(t, dt) + polyblep((t + 0x8000) & 0xFFFF, dt);
+    if value > 0x7FFF {0x7FFF} else if value < -0x7FFF {-0x7FFF} else {value as i16}
+}
+
+pub fn i16blsaw(x: i16, dt: i32) -> i16 {
+    let t = ((x as u16) ^ 0x8000) as i32;
+    let value = i16saw(x) as i32 - polyblep(t, dt);
+    if value > 0x7FFF {0x7FFF} else if value < -0x8000 {-0x8000} else {value as i16}
+}
+
 pub fn i16noise(x: i16) -> i16 {
     // This is actually for 32-bit, but works here too. Maybe not optimal.
     let t = ((x >> 13) ^ x) as u32;
     t.wrapping_mul(t.wrapping_mul(t.wrapping_mul(60493)).wrapping_add(19990303)).wrapping_add(1376312589) as i16
 }
+
+// Single-cycle waveform for Waveform::Table, of any non-zero length. The phase
+// is used as the index.
+#[derive(Clone, Copy)]
+pub enum Wavetable<'a> {
+    I8(&'a [i8]),
+    I16(&'a [i16]),
+}
+
+impl<'a> Wavetable<'a> {
+    pub fn lookup(&self, x: i16) -> i16 {
+        match *self {
+            Wavetable::I8(data) => (data[((x as u16 as usize) * data.len()) >> 16] as i16) << 8,
+            Wavetable::I16(data) => data[((x as u16 as usize) * data.len()) >> 16],
+        }
+    }
+}
@@ -0,0 +1,336 @@
+use lattefm::instrument::{Instrument, Macro, MacroState};
+use lattefm::channel::Command;
+use lattefm::tune::{Tune, Song};
+use lattefm::player::{Player, ChannelPlayer};
+
+const fn instrument(attack: u16, decay: u16, release: u16) -> Instrument<'static> {
+    Instrument {
+        amplitude: u16::MAX/2,
+        attack,
+        decay,
+        release,
+        ..Instrument::DEFAULT
+    }
+}
+
+fn render(tune: &Tune, frames: usize) -> Vec<i16> {
+    let mut channels = [ChannelPlayer::default(); 4];
+    let mut player = Player::new(tune, &mut channels);
+    let mut out = vec![0i16; frames*2];
+    player.generate_i16(&mut out);
+    out
+}
+
+// A Play note shorter than attack and decay used to leave the release stage
+// with a negative length, which Wait then ran into.
+#[test]
+fn play_shorter_than_attack_and_decay() {
+    let instruments = [instrument(1<<12, 1<<12, 1<<10)];
+    let commands = [Command::Note(40), Command::Play(1), Command::Wait(200)];
+    let channels: [&[Command]; 1] = [&commands];
+    let songs = [Song { tick_length: 256<<8, channels: &channels }];
+    let tune = Tune { samplerate: 8000, instruments: &instruments, wavetables: &[], songs: &songs };
+
+    let out = render(&tune, 8000*3);
+    // Cut right after the decay.
+    assert!(out[..16000*2].iter().any(|&x| x != 0));
+    assert!(out[16100*2..].iter().all(|&x| x == 0));
+}
+
+// SetTempo has to reach every channel at the same tick, whichever one has it.
+#[test]
+fn tempo_change_is_independent_of_channel() {
+    use lattefm::channel::Command::*;
+    let instruments = [instrument(1<<8, 1<<8, 1<<8)];
+    let with_tempo = [SetInstrument(0), Note(40), Play(2), SetTempo(1200), Note(42), Play(3), Note(44), Play(3), End];
+    let without_tempo = [SetInstrument(0), Note(47), Play(2), Note(49), Play(3), Note(51), Play(3), End];
+
+    let first: [&[Command]; 2] = [&with_tempo, &without_tempo];
+    let songs = [Song { tick_length: 500<<8, channels: &first }];
+    let tune = Tune { samplerate: 8000, instruments: &instruments, wavetables: &[], songs: &songs };
+    let a = render(&tune, 8000);
+
+    let second: [&[Command]; 2] = [&without_tempo, &with_tempo];
+    let songs = [Song { tick_length: 500<<8, channels: &second }];
+    let tune = Tune { samplerate: 8000, instruments: &instruments, wavetables: &[], songs: &songs };
+    let b = render(&tune, 8000);
+
+    assert!(a == b);
+}
+
+// A Play note in the tick of a tempo change used to be timed at the old tempo,
+// ending early or late.
+#[test]
+fn play_lasts_its_ticks_after_tempo_change() {
+    use lattefm::channel::Command::*;
+    let instruments = [instrument(0, 0, 1<<8)];
+    // From 1000 frames per tick to 4000 and to 500.
+    for &(tempo, end) in [(120, 9000), (960, 2000)].iter() {
+        let note = [SetInstrument(0), Note(40), Play(3), End];
+        let tempo_first = [SetTempo(tempo), SetInstrument(0), Note(40), Play(3), End];
+        let tempo_after = [SetTempo(tempo), Wait(20)];
+        let channel_sets: [[&[Command]; 2]; 2] = [[&tempo_first, &[]], [&note, &tempo_after]];
+        for channels in channel_sets.iter() {
+            let songs = [Song { tick_length: 1000<<8, channels }];
+            let tune = Tune { samplerate: 8000, instruments: &instruments, wavetables: &[], songs: &songs };
+            let out = render(&tune, 10000);
+            // Released over the last 500 frames.
+            assert!(out[(end-600)*2..(end-500)*2].iter().any(|&x| x != 0));
+            assert!(out[(end+50)*2..].iter().all(|&x| x == 0));
+        }
+    }
+}
+
+// Looping point envelopes used to keep a channel going forever after End.
+#[test]
+fn looping_envelope_finishes_after_end() {
+    use lattefm::channel::Command::*;
+    use lattefm::instrument::Envelope;
+    let mut looping = instrument(0, 0, 1<<8);
+    looping.envelope = Some(Envelope {
+        points: &[(0, 0), (100, u16::MAX), (200, u16::MAX/2), (300, u16::MAX)],
+        sustain: None,
+        loop_points: Some((1, 3))
+    });
+    let instruments = [looping];
+    let commands = [SetInstrument(0), Note(40), Play(2), End];
+    let channels: [&[Command]; 1] = [&commands];
+    let songs = [Song { tick_length: 500<<8, channels: &channels }];
+    let tune = Tune { samplerate: 8000, instruments: &instruments, wavetables: &[], songs: &songs };
+
+    let mut channels = [ChannelPlayer::default(); 1];
+    let mut player = Player::new(&tune, &mut channels);
+    let mut out = vec![0i16; 8000*2];
+    player.generate_i16(&mut out);
+    assert!(player.is_finished());
+    assert!(out[7000*2..].iter().all(|&x| x == 0));
+}
+
+// Effects used to start in the middle of a tick, with their note lengths
+// counted from there.
+#[test]
+fn sfx_starts_on_next_tick() {
+    use lattefm::channel::Command::*;
+    let instruments = [instrument(0, 0, 1<<8)];
+    let music = [Wait(100)];
+    let channels: [&[Command]; 1] = [&music];
+    let songs = [Song { tick_length: 500<<8, channels: &channels }];
+    let tune = Tune { samplerate: 8000, instruments: &instruments, wavetables: &[], songs: &songs };
+    let effect = [SetInstrument(0), Note(40), Play(2), End];
+
+    let mut channels = [ChannelPlayer::default(); 1];
+    let mut player = Player::new(&tune, &mut channels);
+    let mut out = vec![0i16; 200*2];
+    player.generate_i16(&mut out);
+    assert_eq!(player.play_sfx(&effect, 0, None), Some(0));
+    let mut out = vec![0i16; 800*2];
+    player.generate_i16(&mut out);
+    assert!(out[..300*2].iter().all(|&x| x == 0));
+    assert!(out[300*2..].iter().any(|&x| x != 0));
+}
+
+// Legato notes used to wait for the next control block to change pitch.
+#[test]
+fn legato_note_changes_pitch_on_its_tick() {
+    use lattefm::channel::Command::*;
+    let instruments = [instrument(0, 0, 1<<8)];
+    let commands = [SetInstrument(0), Note(40), Hold(2), Note(76), Wait(2), End];
+    let channels: [&[Command]; 1] = [&commands];
+    let songs = [Song { tick_length: 750<<8, channels: &channels }];
+    let tune = Tune { samplerate: 8000, instruments: &instruments, wavetables: &[], songs: &songs };
+
+    let out = render(&tune, 2000);
+    // The new note is more than three octaves higher.
+    let left: Vec<i16> = out.iter().step_by(2).copied().collect();
+    let crossings = left[1500..1536].windows(2).filter(|x| (x[0] < 0) != (x[1] < 0)).count();
+    assert!(crossings > 6);
+}
+
+// The modulation of point envelope instruments used to stop at the end of a
+// Play note while the envelope was still ringing.
+#[test]
+fn point_envelope_release_keeps_modulation() {
+    use lattefm::channel::Command::*;
+    use lattefm::instrument::Envelope;
+    let mut modulated = instrument(0, 0, 1<<8);
+    modulated.modulator_amplitude = u16::MAX/2;
+    modulated.envelope = Some(Envelope {
+        points: &[(0, 0), (50, u16::MAX), (2000, 0)],
+        sustain: Some(1),
+        loop_points: None
+    });
+    let instruments = [modulated];
+    let played = [SetInstrument(0), Note(40), Play(2), Wait(4), End];
+    let released = [SetInstrument(0), Note(40), Hold(2), KeyOff, Wait(4), End];
+
+    let channels: [&[Command]; 1] = [&played];
+    let songs = [Song { tick_length: 500<<8, channels: &channels }];
+    let tune = Tune { samplerate: 8000, instruments: &instruments, wavetables: &[], songs: &songs };
+    let a = render(&tune, 3000);
+
+    let channels: [&[Command]; 1] = [&released];
+    let songs = [Song { tick_length: 500<<8, channels: &channels }];
+    let tune = Tune { samplerate: 8000, instruments: &instruments, wavetables: &[], songs: &songs };
+    let b = render(&tune, 3000);
+
+    assert!(a[1100*2..].iter().any(|&x| x != 0));
+    assert!(a == b);
+}
+
+#[test]
+fn mixer_applies_gain_and_saturates() {
+    use lattefm::mixer::{Mixer, LimiterState};
+    let mixer = Mixer { gain: 128, ..Default::default() };
+    let mut buffer = [1000, -1000, 1<<20, -(1<<20)];
+    mixer.process(&mut LimiterState::default(), 8000, 1<<15, &mut buffer);
+    assert_eq!(buffer, [500, -500, (1<<15) - 1, -(1<<15)]);
+}
+
+#[test]
+fn soft_clip_bends_above_half_of_full_scale() {
+    use lattefm::mixer::{Mixer, Clipping, LimiterState};
+    let mixer = Mixer { clipping: Clipping::SoftClip, ..Default::default() };
+    let mut buffer = [1<<14, -(1<<14), 3<<13, -(3<<13), 1<<16, 1<<20];
+    mixer.process(&mut LimiterState::default(), 8000, 1<<15, &mut buffer);
+    // Untouched up to the knee, symmetric, rising and below full scale.
+    assert_eq!(&buffer[..2], &[1<<14, -(1<<14)]);
+    assert_eq!(buffer[2], -buffer[3]);
+    assert!(buffer[2] > 1<<14 && buffer[2] < 3<<13);
+    assert!(buffer[2] < buffer[4] && buffer[4] < buffer[5] && buffer[5] < 1<<15);
+}
+
+#[test]
+fn limiter_drops_to_threshold_and_recovers() {
+    use lattefm::mixer::{Mixer, Limiter, LimiterState};
+    let mixer = Mixer {
+        limiter: Some(Limiter { threshold: 1<<15, release: 1<<12 }),
+        ..Default::default()
+    };
+    let mut state = LimiterState::default();
+    let mut buffer = [1<<15, -(1<<15), 1<<13, 1<<13];
+    mixer.process(&mut state, 8000, 1<<15, &mut buffer);
+    assert_eq!(&buffer[..2], &[1<<14, -(1<<14)]);
+    assert!(buffer[2] < 1<<13 && buffer[2] > 1<<12);
+    assert!(state.gain > 1<<15 && state.gain < 1<<16);
+
+    // Fully recovered after the release time.
+    let mut quiet = vec![0; 8000*2];
+    mixer.process(&mut state, 8000, 1<<15, &mut quiet);
+    assert_eq!(state.gain, 1<<16);
+}
+
+fn step_macro(sequence: &Macro, state: &mut MacroState, released: bool, ticks: usize) -> Vec<i32> {
+    (0..ticks).map(|_| state.advance(sequence, released)).collect()
+}
+
+#[test]
+fn macro_without_loop_holds_its_last_value() {
+    let sequence = Macro { values: &[1, 2, 3], loop_point: None, release_point: None };
+    let mut state = MacroState::default();
+    assert_eq!(step_macro(&sequence, &mut state, false, 5), [1, 2, 3, 3, 3]);
+
+    let sequence = Macro { values: &[1, 2, 3], loop_point: Some(1), release_point: None };
+    let mut state = MacroState::default();
+    assert_eq!(step_macro(&sequence, &mut state, false, 6), [1, 2, 3, 2, 3, 2]);
+
+    let empty = Macro { values: &[], loop_point: Some(0), release_point: Some(0) };
+    assert_eq!(step_macro(&empty, &mut MacroState::default(), false, 2), [0, 0]);
+}
+
+#[test]
+fn macro_holds_at_release_point_until_released() {
+    let sequence = Macro { values: &[1, 2, 3, 4], loop_point: None, release_point: Some(1) };
+    let mut state = MacroState::default();
+    assert_eq!(step_macro(&sequence, &mut state, false, 4), [1, 2, 2, 2]);
+    // Released while at the release point.
+    assert_eq!(step_macro(&sequence, &mut state, true, 4), [2, 3, 4, 4]);
+
+    // Released before reaching it.
+    let mut state = MacroState::default();
+    assert_eq!(step_macro(&sequence, &mut state, true, 5), [1, 2, 3, 4, 4]);
+}
+
+#[test]
+fn macro_loop_before_release_point_ends_on_release() {
+    let sequence = Macro { values: &[1, 2, 3, 4, 5], loop_point: Some(1), release_point: Some(2) };
+    let mut state = MacroState::default();
+    assert_eq!(step_macro(&sequence, &mut state, false, 6), [1, 2, 3, 2, 3, 2]);
+    assert_eq!(step_macro(&sequence, &mut state, true, 5), [3, 4, 5, 5, 5]);
+}
+
+#[test]
+fn macro_loop_after_release_point_starts_on_release() {
+    let sequence = Macro { values: &[1, 2, 3, 4, 5], loop_point: Some(3), release_point: Some(1) };
+    let mut state = MacroState::default();
+    assert_eq!(step_macro(&sequence, &mut state, false, 4), [1, 2, 2, 2]);
+    assert_eq!(step_macro(&sequence, &mut state, true, 7), [2, 3, 4, 5, 4, 5, 4]);
+}
+
+// Renders both command lists on one channel and checks that they sound the same.
+fn assert_plays_like(commands: &[Command], flat: &[Command]) {
+    let instruments = [instrument(0, 0, 1<<6)];
+    let render_commands = |commands: &[Command]| {
+        let channels: [&[Command]; 1] = [commands];
+        let songs = [Song { tick_length: 200<<8, channels: &channels }];
+        let tune = Tune { samplerate: 8000, instruments: &instruments, wavetables: &[], songs: &songs };
+        render(&tune, 4000)
+    };
+    assert!(render_commands(commands) == render_commands(flat));
+}
+
+#[test]
+fn call_returns_after_the_call() {
+    use lattefm::channel::Command::*;
+    assert_plays_like(
+        &[SetInstrument(0), Call(5), Note(47), Call(5), End, Note(40), Play(1), Return],
+        &[SetInstrument(0), Note(40), Play(1), Note(47), Note(40), Play(1), End]
+    );
+    // Return outside a call does nothing.
+    assert_plays_like(
+        &[SetInstrument(0), Return, Note(40), Play(1), End],
+        &[SetInstrument(0), Note(40), Play(1), End]
+    );
+}
+
+#[test]
+fn calls_too_deep_skip_the_rest_of_the_innermost_one() {
+    use lattefm::channel::Command::*;
+    assert_plays_like(
+        &[
+            SetInstrument(0), Call(5), Note(40), Play(1), End,
+            Call(9), Note(41), Play(1), Return,
+            Call(13), Note(42), Play(1), Return,
+            Call(17), Note(43), Play(1), Return,
+            Call(21), Note(44), Play(1), Return,
+            Note(45), Play(1), Return
+        ],
+        &[
+            SetInstrument(0),
+            Note(45), Play(1), Note(44), Play(1), Note(42), Play(1),
+            Note(41), Play(1), Note(40), Play(1), End
+        ]
+    );
+}
+
+#[test]
+fn nested_loops_repeat_inside_out() {
+    use lattefm::channel::Command::*;
+    assert_plays_like(
+        &[
+            SetInstrument(0),
+            LoopStart, Note(40), Play(1), LoopStart, Note(44), Play(1), LoopEnd(2), LoopEnd(2),
+            Note(47), Play(1), End
+        ],
+        &[
+            SetInstrument(0),
+            Note(40), Play(1), Note(44), Play(1), Note(44), Play(1),
+            Note(40), Play(1), Note(44), Play(1), Note(44), Play(1),
+            Note(47), Play(1), End
+        ]
+    );
+    // A single pass plays once, and LoopEnd outside a loop does nothing.
+    assert_plays_like(
+        &[SetInstrument(0), LoopEnd(3), LoopStart, Note(40), Play(1), LoopEnd(1), End],
+        &[SetInstrument(0), Note(40), Play(1), End]
+    );
+}